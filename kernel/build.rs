@@ -1,10 +1,78 @@
 fn main() {
+    use linker::{Script, Section};
     use std::{env, fs, path::PathBuf};
 
-    let ld = &PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("linker.ld");
-    fs::write(ld, format!("{}", linker::Script)).unwrap();
+    // 只读段和可写段按页对齐，以便分别设置权限
+    let script = Script::builder()
+        .load(linker::LOAD)
+        .page_size(4096)
+        .section(
+            Section::new(".text")
+                .input(".text.entry")
+                .input(".text .text.*"),
+        )
+        .section(
+            Section::new(".rodata")
+                .page_aligned()
+                .symbol("_rodata")
+                .input(".rodata .rodata.*")
+                .input(".srodata .srodata.*"),
+        )
+        .section(
+            Section::new(".bench")
+                .align(8)
+                .symbol("_bench_start")
+                .keep(".bench")
+                .symbol("_bench_end"),
+        )
+        .section(
+            Section::new(".param")
+                .align(8)
+                .symbol("_param_start")
+                .keep(".param")
+                .symbol("_param_end"),
+        )
+        .section(
+            Section::new(".rela.dyn")
+                .symbol("_rela_start")
+                .input(".rela.dyn .rela.*")
+                .symbol("_rela_end"),
+        )
+        .section(
+            Section::new(".data")
+                .page_aligned()
+                .symbol("_data")
+                .input(".data .data.*")
+                .input(".sdata .sdata.*"),
+        )
+        .section(
+            Section::new(".bss")
+                .align(8)
+                .symbol("_bss")
+                .input(".bss .bss.*")
+                .input(".sbss .sbss.*"),
+        )
+        .build();
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let ld = &out.join("linker.ld");
+    fs::write(ld, script.to_string()).unwrap();
+    fs::write(out.join("linker.rs"), script.constants().to_string()).unwrap();
+
+    // 嵌入下一阶段载荷，没有指定时写一个空文件
+    let payload = out.join("payload.bin");
+    match env::var_os("PAYLOAD") {
+        Some(path) => {
+            fs::copy(&path, payload).unwrap();
+            println!("cargo:rerun-if-changed={}", PathBuf::from(path).display());
+        }
+        None => fs::write(payload, []).unwrap(),
+    }
 
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-env-changed=LOG");
+    println!("cargo:rerun-if-env-changed=PAYLOAD");
     println!("cargo:rustc-link-arg=-T{}", ld.display());
+    println!("cargo:rustc-link-arg=-pie");
+    println!("cargo:rustc-link-arg=--no-dynamic-linker");
 }
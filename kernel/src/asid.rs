@@ -0,0 +1,160 @@
+﻿//! 地址空间标识符（ASID）分配。
+//!
+//! ASID 按代分配。一代的 ASID 用完后进入下一代，之前分配的 ASID 全部作废，地址空间下次激活时重新分配。
+//! 每个硬件线程在新的一代中第一次激活地址空间时刷新整个 TLB，此后切换地址空间不需要刷新。
+//!
+//! ASID 0 保留给启动阶段和载荷。硬件不支持 ASID 或以 `asid=off` 关闭时，每次切换都刷新 TLB。
+
+use crate::{
+    boot::PagingMode, hart, layout::KernelLayout, param::Param, space::AddressSpace, Global, LAYOUT,
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use page_table::{Sv39, Sv48, Sv57, VmFlags};
+use riscv::register::satp;
+use spin::Mutex;
+
+param! {
+    /// 是否使用 ASID。
+    pub(crate) static ASID: Param<bool> = Param::new(
+        "asid",
+        "是否使用 ASID，关闭时每次切换地址空间都刷新 TLB",
+        true,
+    );
+}
+
+/// ASID 在标签中占的位数。
+const ASID_BITS: u32 = 16;
+
+/// 地址空间持有的 ASID，高位是代，低 16 位是 ASID，0 表示还没有分配。
+#[derive(Clone, Copy, Default)]
+pub(crate) struct AsidTag(u64);
+
+impl AsidTag {
+    #[inline]
+    fn generation(self) -> u64 {
+        self.0 >> ASID_BITS
+    }
+
+    /// 标签中的 ASID。
+    #[inline]
+    pub fn asid(self) -> usize {
+        (self.0 & ((1 << ASID_BITS) - 1)) as _
+    }
+}
+
+struct Allocator {
+    /// 当前的代，从 1 开始。
+    generation: u64,
+    /// 这一代中下一个分配的 ASID。
+    next: usize,
+    /// ASID 的数量，不超过 1 时不使用 ASID。
+    max: usize,
+}
+
+static ALLOCATOR: Mutex<Allocator> = Mutex::new(Allocator {
+    generation: 1,
+    next: 1,
+    max: 0,
+});
+
+/// 每个硬件线程最后一次刷新 TLB 时的代。
+static FLUSHED: [AtomicU64; KernelLayout::MAX_HARTS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    [ZERO; KernelLayout::MAX_HARTS]
+};
+
+/// 检测支持的 ASID 位数。
+fn detect() -> u32 {
+    const MASK: usize = (1 << ASID_BITS) - 1;
+    let mut mask = MASK << 44;
+    unsafe {
+        core::arch::asm!(
+            "csrr  {old}, satp",
+            "csrrs zero, satp, {mask}",
+            "csrr  {mask}, satp",
+            "csrw  satp, {old}",
+            old = out(reg) _,
+            mask = inlateout(reg) mask,
+        );
+    }
+    ((mask >> 44) & MASK).trailing_ones()
+}
+
+/// 检测 ASID 位数并初始化分配器。
+pub(crate) fn init() {
+    let bits = detect();
+    let enabled = ASID.get() && bits > 0;
+    ALLOCATOR.lock().max = if enabled { 1 << bits } else { 0 };
+    if enabled {
+        log::info!("asid: {bits} bits");
+    } else {
+        log::info!("asid: disabled ({bits} bits supported), flush on every switch");
+    }
+}
+
+/// 确保 `tag` 持有当前一代的 ASID。
+///
+/// 返回 ASID，以及切换到这个 ASID 之前是否需要刷新当前硬件线程的 TLB。
+pub(crate) fn assign(tag: &mut AsidTag) -> (usize, bool) {
+    let generation = {
+        let mut allocator = ALLOCATOR.lock();
+        if allocator.max <= 1 {
+            return (0, true);
+        }
+        if tag.generation() != allocator.generation {
+            if allocator.next == allocator.max {
+                allocator.generation += 1;
+                allocator.next = 1;
+                log::debug!("asid rollover, generation {}", allocator.generation);
+            }
+            *tag = AsidTag((allocator.generation << ASID_BITS) | allocator.next as u64);
+            allocator.next += 1;
+        }
+        allocator.generation
+    };
+    let flushed = FLUSHED[hart::id()].swap(generation, Ordering::AcqRel);
+    (tag.asid(), flushed != generation)
+}
+
+/// `tag` 在当前一代中的 ASID，没有使用 ASID、还没有分配或已经过期时返回 `None`。
+pub(crate) fn current(tag: AsidTag) -> Option<usize> {
+    let allocator = ALLOCATOR.lock();
+    if allocator.max > 1 && tag.generation() == allocator.generation {
+        Some(tag.asid())
+    } else {
+        None
+    }
+}
+
+bench!(switch, |b| match satp::read().mode() {
+    satp::Mode::Sv57 => switch::<Sv57>(b),
+    satp::Mode::Sv48 => switch::<Sv48>(b),
+    _ => switch::<Sv39>(b),
+});
+
+/// 在两个映射了内核的地址空间之间来回切换，每次切换后访问一些内核页。
+///
+/// 映射不是全局的，用 `asid=on` 和 `asid=off` 分别运行可以比较 ASID 的收益。
+fn switch<Meta: PagingMode>(b: &mut crate::bench::Bencher) {
+    const PAGES: usize = 32;
+    let saved = satp::read().bits();
+    let mut spaces = [(); 2].map(|_| {
+        let mut space = AddressSpace::<Meta, Global>::new(Global);
+        space.kernel(VmFlags::build_from_str("DA__XWRV"));
+        space
+    });
+    let start = unsafe { LAYOUT.start() };
+    b.iter(|| {
+        for space in &mut spaces {
+            space.activate();
+            for i in 0..PAGES {
+                unsafe { ((start + (i << 12)) as *const u8).read_volatile() };
+            }
+        }
+    });
+    unsafe {
+        core::arch::asm!("csrw satp, {0}", in(reg) saved);
+        riscv::asm::sfence_vma_all();
+    }
+}
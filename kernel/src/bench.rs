@@ -0,0 +1,271 @@
+﻿//! 微基准测试。
+//!
+//! 任何模块都可以用 [`bench!`] 声明基准测试，它们被放在 `.bench` 段中，启动时由 [`run`] 发现并运行。
+//! 每个结果输出为一行：
+//!
+//! ```text
+//! BENCH name=kernel::timer::instant_now iters=100 cycles.min=12 cycles.median=13 ... ns.mean=100 ... instret.mean=8
+//! ```
+//!
+//! 全部运行完输出一行 `BENCH-END`。
+
+use crate::{
+    param::Param,
+    pmu::{CounterGroup, Event, MAX_EVENTS},
+    timer::{self, Instant},
+};
+use alloc::vec::Vec;
+use core::{fmt, hint::black_box};
+use riscv::register::cycle;
+
+param! {
+    /// 要运行的基准测试。
+    pub(crate) static BENCH: Param<&str> = Param::new(
+        "bench",
+        "要运行的基准测试，逗号分隔的名字片段，all 表示全部",
+        "",
+    );
+}
+
+param! {
+    /// 每个基准测试计时的迭代次数。
+    pub(crate) static ITERS: Param<usize> = Param::new("bench-iters", "计时的迭代次数", 100);
+}
+
+param! {
+    /// 每个基准测试预热的迭代次数。
+    pub(crate) static WARMUP: Param<usize> = Param::new("bench-warmup", "预热的迭代次数", 10);
+}
+
+param! {
+    /// 额外测量的 PMU 事件。
+    pub(crate) static EVENTS: Param<&str> =
+        Param::new("bench-events", "额外测量的 PMU 事件，逗号分隔", "");
+}
+
+/// 声明一个基准测试。
+///
+/// ```ignore
+/// bench!(instant_now, |b| b.iter(Instant::now));
+/// ```
+macro_rules! bench {
+    ($name:ident, $run:expr) => {
+        // 匿名常量里的静态变量不占用模块的名字，可以与同名函数共存
+        const _: () = {
+            #[used]
+            #[link_section = ".bench"]
+            static BENCH: $crate::bench::Bench = $crate::bench::Bench {
+                name: concat!(module_path!(), "::", stringify!($name)),
+                run: $run,
+            };
+        };
+    };
+}
+
+/// 一个基准测试，由 [`bench!`] 生成。
+#[repr(C)]
+pub(crate) struct Bench {
+    /// 模块路径加上名字。
+    pub name: &'static str,
+    pub run: fn(&mut Bencher),
+}
+
+/// 传给基准测试的计时器。
+pub(crate) struct Bencher {
+    warmup: usize,
+    iters: usize,
+    events: [Event; MAX_EVENTS],
+    events_len: usize,
+    result: Option<Report>,
+}
+
+impl Bencher {
+    /// [`iter`](Self::iter) 调用 `f` 的总次数，包括预热。
+    pub fn iterations(&self) -> usize {
+        self.warmup + self.iters
+    }
+
+    /// 反复运行 `f` 并计时。
+    ///
+    /// 一个基准测试只应调用一次，多次调用时只保留最后一次的结果。
+    pub fn iter<T>(&mut self, mut f: impl FnMut() -> T) {
+        for _ in 0..self.warmup {
+            black_box(f());
+        }
+        let mut cycles = Vec::with_capacity(self.iters);
+        let mut ticks = Vec::with_capacity(self.iters);
+        // 每次只直接读 `cycle` 和 `time`，计数器组只在循环外读，读固件计数器的 SBI 调用不计入样本
+        let group = CounterGroup::new(&self.events[..self.events_len]);
+        for _ in 0..self.iters {
+            let t0 = Instant::now();
+            let c0 = cycle::read64();
+            black_box(f());
+            let c1 = cycle::read64();
+            let t1 = Instant::now();
+            cycles.push(c1.wrapping_sub(c0));
+            ticks.push(t1.ticks() - t0.ticks());
+        }
+        let total = group.finish();
+        let mut report = Report {
+            iters: self.iters,
+            cycles: Stats::new(&mut cycles),
+            ns: Stats::new(&mut ticks).map(|t| timer::ticks_to_duration(t).as_nanos() as _),
+            instret: total.instret / self.iters as u64,
+            events: [(Event::CYCLES, None); MAX_EVENTS],
+            events_len: total.events().len(),
+        };
+        for (slot, (event, value)) in report.events.iter_mut().zip(total.events()) {
+            *slot = (*event, value.map(|v| v / self.iters as u64));
+        }
+        self.result = Some(report);
+    }
+}
+
+/// 一组测量值的统计量。
+#[derive(Clone, Copy)]
+struct Stats {
+    min: u64,
+    median: u64,
+    mean: u64,
+    stddev: u64,
+}
+
+impl Stats {
+    /// 统计 `values`，会把它排序。
+    fn new(values: &mut [u64]) -> Self {
+        if values.is_empty() {
+            return Self {
+                min: 0,
+                median: 0,
+                mean: 0,
+                stddev: 0,
+            };
+        }
+        values.sort_unstable();
+        let n = values.len() as u128;
+        let sum = values.iter().map(|v| *v as u128).sum::<u128>();
+        let mean = sum / n;
+        let variance = values
+            .iter()
+            .map(|v| (*v as u128).abs_diff(mean).pow(2))
+            .sum::<u128>()
+            / n;
+        Self {
+            min: values[0],
+            median: values[values.len() / 2],
+            mean: mean as _,
+            stddev: isqrt(variance) as _,
+        }
+    }
+
+    fn map(self, f: impl Fn(u64) -> u64) -> Self {
+        Self {
+            min: f(self.min),
+            median: f(self.median),
+            mean: f(self.mean),
+            stddev: f(self.stddev),
+        }
+    }
+}
+
+/// 整数平方根，向下取整。
+fn isqrt(n: u128) -> u128 {
+    if n < 2 {
+        return n;
+    }
+    let mut x = n;
+    let mut y = (x + 1) / 2;
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
+
+/// 一个基准测试的结果。
+struct Report {
+    iters: usize,
+    cycles: Stats,
+    ns: Stats,
+    /// 平均每次迭代的指令数。
+    instret: u64,
+    /// 平均每次迭代的事件计数。
+    events: [(Event, Option<u64>); MAX_EVENTS],
+    events_len: usize,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "iters={}", self.iters)?;
+        for (name, stats) in [("cycles", self.cycles), ("ns", self.ns)] {
+            write!(
+                f,
+                " {name}.min={} {name}.median={} {name}.mean={} {name}.stddev={}",
+                stats.min, stats.median, stats.mean, stats.stddev,
+            )?;
+        }
+        write!(f, " instret.mean={}", self.instret)?;
+        for (event, value) in &self.events[..self.events_len] {
+            match value {
+                Some(value) => write!(f, " {}.mean={value}", event.name())?,
+                None => write!(f, " {}.mean=n/a", event.name())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 链接脚本收集的所有基准测试。
+fn benches() -> &'static [Bench] {
+    let (start, end): (usize, usize);
+    unsafe {
+        core::arch::asm!(
+            "lla {0}, _bench_start",
+            "lla {1}, _bench_end",
+            out(reg) start,
+            out(reg) end,
+        );
+        core::slice::from_raw_parts(
+            start as *const Bench,
+            (end - start) / core::mem::size_of::<Bench>(),
+        )
+    }
+}
+
+/// 运行 `bench` 参数选中的基准测试。
+pub(crate) fn run() {
+    let filter = BENCH.get();
+    if filter.is_empty() {
+        return;
+    }
+    let mut bencher = Bencher {
+        warmup: WARMUP.get(),
+        iters: ITERS.get().max(1),
+        events: [Event::CYCLES; MAX_EVENTS],
+        events_len: 0,
+        result: None,
+    };
+    for name in EVENTS.get().split(',').filter(|s| !s.is_empty()) {
+        match Event::from_name(name) {
+            Some(_) if bencher.events_len == MAX_EVENTS => {
+                log::warn!("at most {MAX_EVENTS} events, {name} ignored")
+            }
+            Some(event) => {
+                bencher.events[bencher.events_len] = event;
+                bencher.events_len += 1;
+            }
+            None => log::warn!("unknown event {name}"),
+        }
+    }
+    let selected = |name: &str| filter == "all" || filter.split(',').any(|f| name.contains(f));
+    for bench in benches().iter().filter(|b| selected(b.name)) {
+        log::info!("bench {}", bench.name);
+        bencher.result = None;
+        (bench.run)(&mut bencher);
+        match bencher.result.take() {
+            Some(report) => println!("BENCH name={} {report}", bench.name),
+            None => log::warn!("bench {} did not call Bencher::iter", bench.name),
+        }
+    }
+    println!("BENCH-END");
+}
@@ -1,50 +1,220 @@
 ﻿use crate::layout::KernelLayout;
-use core::{arch::asm, ptr::NonNull};
-use page_table::{MmuMeta, Pte, Sv39, VAddr, VmFlags, VmMeta, PPN};
+use core::{arch::asm, ops::Range};
+use page_table::{MmuMeta, Pte, Sv39, Sv48, Sv57, VAddr, VmFlags, VmMeta, PPN};
+use riscv::register::satp;
+
+/// 可以由 satp 启用的分页模式。
+pub(crate) trait PagingMode: VmMeta + 'static {
+    /// 对应的 satp 模式。
+    const MODE: satp::Mode;
+    /// 虚地址位数。
+    const VA_BITS: usize;
+}
+
+impl PagingMode for Sv39 {
+    const MODE: satp::Mode = satp::Mode::Sv39;
+    const VA_BITS: usize = 39;
+}
+
+impl PagingMode for Sv48 {
+    const MODE: satp::Mode = satp::Mode::Sv48;
+    const VA_BITS: usize = 48;
+}
+
+impl PagingMode for Sv57 {
+    const MODE: satp::Mode = satp::Mode::Sv57;
+    const VA_BITS: usize = 57;
+}
+
+/// 1 GiB 大页所在的页表级别。
+pub(crate) const GIGA: usize = 2;
+
+/// 启动页表占用的页数，按 Sv57 计算。
+///
+/// 根页表，线性区的前 128 GiB 需要 `MAX_LEVEL - GIGA` 个中间页表；
+/// 内核的恒等映射和高地址映射各自最多跨两个 `MAX_LEVEL - GIGA` 级的路径，
+/// 起始位置不与 2 MiB 对齐时还需要一个 2 MiB 页表和一个 4 KiB 页表。
+pub(crate) const BOOT_PT_PAGES: usize =
+    1 + (Sv57::MAX_LEVEL - GIGA) + 2 * (2 * (Sv57::MAX_LEVEL - GIGA) + 2);
+
+/// 探测硬件支持的最高分页模式。
+///
+/// satp 是 WARL 的，从高到低依次写入，写入不支持的模式不会生效。
+/// 尝试时用栈上的一页作根页表，以根页表的叶子项恒等映射低半地址空间，确认后恢复物理地址空间。
+///
+/// # Safety
+///
+/// 在物理地址空间中调用。
+pub(crate) unsafe fn probe() -> satp::Mode {
+    #[repr(C, align(4096))]
+    struct Page([usize; 512]);
+
+    let mut root = Page([0; 512]);
+    let root = root.0.as_mut_ptr() as usize;
+    if try_mode::<Sv57>(root) {
+        Sv57::MODE
+    } else if try_mode::<Sv48>(root) {
+        Sv48::MODE
+    } else if try_mode::<Sv39>(root) {
+        Sv39::MODE
+    } else {
+        sbi_rt::system_reset(
+            sbi_rt::RESET_TYPE_SHUTDOWN,
+            sbi_rt::RESET_REASON_SYSTEM_FAILURE,
+        );
+        unreachable!()
+    }
+}
+
+/// 以 `root` 为根页表尝试启动 `Meta` 模式的地址转换，然后恢复物理地址空间。
+///
+/// 返回硬件是否接受这个模式。
+unsafe fn try_mode<Meta: PagingMode>(root: usize) -> bool {
+    let flags = VmFlags::<Meta>::build_from_str("DA__XWRV");
+    let entries = (1 << Meta::PAGE_BITS) / core::mem::size_of::<Pte<Meta>>();
+    let bits = Meta::pages_in_table(Meta::MAX_LEVEL - 1).trailing_zeros() as usize;
+    let table = core::slice::from_raw_parts_mut(root as *mut Pte<Meta>, entries);
+    for (i, pte) in table.iter_mut().take(entries / 2).enumerate() {
+        *pte = flags.build_pte(PPN::new(i << bits));
+    }
+    asm!("csrw satp, {0}", in(reg) satp_bits::<Meta>(root));
+    riscv::asm::sfence_vma_all();
+    let accepted = satp::read().mode() == Meta::MODE;
+    asm!("csrw satp, zero");
+    riscv::asm::sfence_vma_all();
+    accepted
+}
+
+/// 以 `Meta` 模式、`root` 为根页表、ASID 为 0 的 satp 值。
+#[inline(always)]
+fn satp_bits<Meta: PagingMode>(root: usize) -> usize {
+    (Meta::MODE as usize) << 60 | root >> Meta::PAGE_BITS
+}
+
+/// 分页模式 `mode` 的线性区偏移。
+pub(crate) fn linear_offset(mode: satp::Mode) -> usize {
+    let va_bits = match mode {
+        satp::Mode::Sv57 => Sv57::VA_BITS,
+        satp::Mode::Sv48 => Sv48::VA_BITS,
+        _ => Sv39::VA_BITS,
+    };
+    linker::linear_offset(va_bits)
+}
 
 /// 启动页表。
-pub(crate) struct BootPageTable(pub NonNull<Pte<Sv39>>);
+///
+/// 保存根页表的物理地址，中间页表依次放在根页表之后。
+pub(crate) struct BootPageTable(pub usize);
 
 impl BootPageTable {
-    /// 根据内核实际位置初始化启动页表，然后启动地址转换跃迁到高地址，并设置内核对用户页的访问权限。
+    /// 以 [`probe`] 选中的分页模式 `mode` 初始化启动页表，然后启动地址转换跃迁到高地址，并设置内核对用户页的访问权限。
     ///
     /// # Safety
     ///
     /// 调用前后位于不同的地址空间，必须内联。
     #[inline(always)]
-    pub unsafe fn launch(&self, layout: &KernelLayout) -> usize {
-        use riscv::register::satp;
-        const FLAGS: VmFlags<Sv39> = VmFlags::build_from_str("DAG_XWRV");
-
-        let start = layout.v_to_p(layout.start());
-        let offset = layout.offset();
-        // 确保虚实地址在 1 GiB 内对齐
-        assert!(offset.trailing_zeros() >= 30);
-        let table = unsafe { core::slice::from_raw_parts_mut(self.0.as_ptr(), 512) };
-        // 映射跳板页
-        let base = VAddr::<Sv39>::new(start).floor().index_in(Sv39::MAX_LEVEL);
-        table[base] = FLAGS.build_pte(PPN::new(base << 18));
-        // 映射物理地址空间的前 128 GiB
-        let base = VAddr::<Sv39>::new(offset).floor().index_in(Sv39::MAX_LEVEL);
-        table[base..]
-            .iter_mut()
-            .take(128)
-            .enumerate()
-            .for_each(|(i, pte)| *pte = FLAGS.build_pte(PPN::new(i << 18)));
-        // 启动地址转换
-        satp::set(
-            satp::Mode::Sv39,
-            0,
-            self.0.as_ptr() as usize >> Sv39::PAGE_BITS,
-        );
+    pub unsafe fn launch(&self, layout: &KernelLayout, mode: satp::Mode) {
+        let satp = match mode {
+            satp::Mode::Sv57 => self.init::<Sv57>(layout),
+            satp::Mode::Sv48 => self.init::<Sv48>(layout),
+            _ => self.init::<Sv39>(layout),
+        };
+        asm!("csrw satp, {0}", in(reg) satp);
         // 此时原本的地址空间还在，所以不用刷快表
         // riscv::asm::sfence_vma_all();
         // 跳到高页面对应位置
-        Self::jump_higher(offset);
+        Self::jump_higher(layout.offset());
         // 设置内核可访问用户页
-        let mut sstatus = 1usize << 18;
-        asm!("csrrs {0}, sstatus, {0}", inlateout(reg) sstatus);
-        sstatus | (1usize << 18)
+        Self::allow_user_access();
+    }
+
+    /// 使用已经初始化的启动页表启动地址转换，跃迁到高地址，并设置内核对用户页的访问权限。
+    ///
+    /// 副核复用主核建立的启动页表，直接调用这个函数。分页模式取自 `satp` 的 MODE 域。
+    ///
+    /// # Safety
+    ///
+    /// 调用前后位于不同的地址空间，必须内联。
+    #[inline(always)]
+    pub unsafe fn enable(&self, layout: &KernelLayout, satp: usize) {
+        const MODE_MASK: usize = 0xf << 60;
+        let satp = (satp & MODE_MASK) | (self.0 >> Sv39::PAGE_BITS);
+        asm!("csrw satp, {0}", in(reg) satp);
+        Self::jump_higher(layout.offset());
+        Self::allow_user_access();
+    }
+
+    /// 以 `Meta` 模式初始化启动页表，返回启用它的 satp 值。
+    unsafe fn init<Meta: PagingMode>(&self, layout: &KernelLayout) -> usize {
+        const COUNT: usize = 128;
+        // 不按段区分权限，建立内核地址空间后就不再使用
+        let flags = VmFlags::<Meta>::build_from_str("DAG_XWRV");
+        let page = 1 << Meta::PAGE_BITS;
+        let giga = Meta::pages_in_table(GIGA - 1) << Meta::PAGE_BITS;
+        // 清空启动页表区域
+        core::slice::from_raw_parts_mut(self.0 as *mut u8, BOOT_PT_PAGES * page).fill(0);
+        let mut next = self.0 + page;
+        // 映射物理地址空间的前 128 GiB，线性区偏移与 1 GiB 对齐，总可以使用 1 GiB 大页
+        self.map::<Meta>(&mut next, layout.offset(), 0..COUNT * giga, flags);
+        // 映射内核镜像、启动栈、启动页表和所在 1 GiB 的剩余部分，内核可能加载在前 128 GiB 之外。
+        // 加载位置只保证 4 KiB 对齐，按对齐依次使用 4 KiB、2 MiB 和 1 GiB 的页
+        let start = layout.v_to_p(layout.start());
+        let end = (layout.v_to_p(layout.boot_pt_end()) + giga - 1) & !(giga - 1);
+        self.map::<Meta>(&mut next, layout.offset(), start..end, flags);
+        // 映射跳板，即同一范围的恒等映射，启动地址转换前后的几条指令在这里执行
+        self.map::<Meta>(&mut next, 0, start..end, flags);
+        debug_assert!(next <= self.0 + BOOT_PT_PAGES * page);
+        satp_bits::<Meta>(self.0)
+    }
+
+    /// 在启动页表中把物理地址范围 `range` 映射到加上 `offset` 的虚地址，缺少的中间页表从 `next` 依次取用。
+    ///
+    /// 每次使用虚地址、物理地址和剩余长度都允许的最大页，最大 1 GiB；已经被大页映射的部分跳过。
+    #[inline(always)]
+    unsafe fn map<Meta: VmMeta>(
+        &self,
+        next: &mut usize,
+        offset: usize,
+        range: Range<usize>,
+        flags: VmFlags<Meta>,
+    ) {
+        let mut paddr = range.start;
+        while paddr < range.end {
+            let vpn = VAddr::<Meta>::new(paddr.wrapping_add(offset)).floor();
+            let mut table = self.0 as *mut Pte<Meta>;
+            let mut level = Meta::MAX_LEVEL;
+            loop {
+                let pages = match level {
+                    0 => 1,
+                    _ => Meta::pages_in_table(level - 1),
+                };
+                let size = pages << Meta::PAGE_BITS;
+                let entry = &mut *table.add(vpn.index_in(level));
+                if entry.is_valid() && entry.is_leaf() {
+                    // 已经映射，跳到这个大页之后
+                    paddr = (paddr & !(size - 1)) + size;
+                    break;
+                }
+                if level <= GIGA && paddr & (size - 1) == 0 && paddr + size <= range.end {
+                    *entry = flags.build_pte(PPN::new(paddr >> Meta::PAGE_BITS));
+                    paddr += size;
+                    break;
+                }
+                if !entry.is_valid() {
+                    *entry = VmFlags::VALID.build_pte(PPN::new(*next >> Meta::PAGE_BITS));
+                    *next += 1 << Meta::PAGE_BITS;
+                }
+                table = (entry.ppn().val() << Meta::PAGE_BITS) as _;
+                level -= 1;
+            }
+        }
+    }
+
+    /// 设置 `sstatus.SUM`，允许内核访问用户页。
+    #[inline(always)]
+    unsafe fn allow_user_access() {
+        let sstatus = 1usize << 18;
+        asm!("csrs sstatus, {0}", in(reg) sstatus);
     }
 
     /// 向上跳到距离为 `offset` 的新地址然后继续执行。
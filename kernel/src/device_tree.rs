@@ -0,0 +1,116 @@
+﻿use crate::LAYOUT;
+use core::{ops::Range, ptr::NonNull};
+use dtb_walker::{Dtb, HeaderError::*};
+use spin::Once;
+
+/// 扁平设备树头的魔数。
+const FDT_MAGIC: u32 = 0xd00d_feed;
+/// 头部的字节数（版本 17）。
+const HEADER_SIZE: usize = 40;
+
+/// 设备树头校验错误。
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum FdtError {
+    /// 魔数不匹配。
+    BadMagic(u32),
+    /// 不支持的版本，记录 `last_comp_version`。
+    Version(u32),
+    /// `totalsize` 小于头部。
+    Truncated(usize),
+    /// 结构块、字符串块或 memreserve 块超出 `totalsize`。
+    BlockOutOfBounds,
+}
+
+/// 只读的扁平设备树。
+pub(crate) struct DeviceTree {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+unsafe impl Send for DeviceTree {}
+unsafe impl Sync for DeviceTree {}
+
+/// 重定位到内核内存中的设备树。
+static RELOCATED: Once<DeviceTree> = Once::new();
+
+impl DeviceTree {
+    /// 校验虚地址 `ptr` 处的设备树头。
+    ///
+    /// # Safety
+    ///
+    /// `ptr` 处至少可读一个设备树头，校验通过后 `totalsize` 字节都可读且不会被修改。
+    pub unsafe fn from_raw(ptr: *const u8) -> Result<Self, FdtError> {
+        let header = |i: usize| u32::from_be((ptr as *const u32).add(i).read_unaligned());
+        let magic = header(0);
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let len = header(1) as usize;
+        if len < HEADER_SIZE {
+            return Err(FdtError::Truncated(len));
+        }
+        let last_comp_version = header(6);
+        if last_comp_version > 17 {
+            return Err(FdtError::Version(last_comp_version));
+        }
+        let struct_end = header(2) as usize + header(9) as usize;
+        let strings_end = header(3) as usize + header(8) as usize;
+        if struct_end > len || strings_end > len || header(4) as usize >= len {
+            return Err(FdtError::BlockOutOfBounds);
+        }
+        Ok(Self {
+            ptr: NonNull::new_unchecked(ptr as _),
+            len,
+        })
+    }
+
+    /// 用于遍历的设备树。
+    pub fn dtb(&self) -> Dtb<'static> {
+        unsafe {
+            Dtb::from_raw_parts_filtered(self.ptr.as_ptr(), |e| {
+                matches!(e, Misaligned(4) | LastCompVersion(_))
+            })
+        }
+        .unwrap()
+    }
+
+    /// 设备树的全部字节。
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// 设备树的物理地址范围。
+    pub fn p_range(&self) -> Range<usize> {
+        let start = unsafe { LAYOUT.v_to_p(self.ptr.as_ptr() as _) };
+        start..start + self.len
+    }
+
+    /// 遍历 memreserve 块描述的物理地址范围。
+    pub fn mem_reserve(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        let bytes = self.as_bytes();
+        let offset = u32::from_be_bytes(bytes[16..20].try_into().unwrap()) as usize;
+        bytes[offset..]
+            .chunks_exact(16)
+            .map(|entry| {
+                let address = u64::from_be_bytes(entry[..8].try_into().unwrap()) as usize;
+                let size = u64::from_be_bytes(entry[8..].try_into().unwrap()) as usize;
+                address..address + size
+            })
+            .take_while(|range| !(range.start == 0 && range.is_empty()))
+    }
+}
+
+/// 保存重定位后的设备树。
+pub(crate) fn init(relocated: DeviceTree) {
+    RELOCATED.call_once(|| relocated);
+}
+
+/// 重定位后的设备树。
+pub(crate) fn get() -> &'static DeviceTree {
+    RELOCATED.get().expect("device tree is not relocated yet")
+}
+
+/// 设备树中的大端整数，可能是 1 个或 2 个 cell。
+pub(crate) fn be_usize(value: &[u8]) -> usize {
+    value.iter().fold(0, |acc, b| (acc << 8) | *b as usize)
+}
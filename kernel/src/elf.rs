@@ -0,0 +1,356 @@
+﻿use crate::space::{AddressSpace, PageManager, PagePolicy};
+use core::ops::Range;
+use page_table::{VAddr, VmFlags, VmMeta, VPN};
+
+/// ELF 解析或加载错误。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ElfError {
+    /// 文件短于头部描述的结构。
+    Truncated,
+    /// 魔数不匹配。
+    BadMagic,
+    /// 不是 ELF64。
+    NotElf64,
+    /// 不是小端序。
+    NotLittleEndian,
+    /// ELF 版本不是 1。
+    BadVersion,
+    /// 不是 RISC-V 文件。
+    NotRiscV,
+    /// 不是可执行文件。
+    NotExecutable,
+    /// 程序头表项大小不对。
+    BadProgramHeader,
+    /// 没有可加载的段。
+    NoLoadableSegment,
+    /// 第 `0` 个程序头描述的文件内容超出文件。
+    SegmentOutOfFile(usize),
+    /// 第 `0` 个程序头的文件大小超过内存大小。
+    FileSizeExceedsMemSize(usize),
+    /// 第 `0` 个程序头的虚地址和文件偏移在页内不同余。
+    Misaligned(usize),
+    /// 第 `0` 个程序头的虚地址加内存大小溢出。
+    SizeOverflow(usize),
+    /// 第 `0` 个程序头没有任何访问权限。
+    NoPermission(usize),
+    /// 第 `0` 个程序头与其他段或地址空间中已有的段重叠。
+    Overlap(usize),
+}
+
+const EI_NIDENT: usize = 16;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// 检查前 4 个字节是否是 ELF 魔数。
+#[inline]
+pub(crate) fn is_elf(image: &[u8]) -> bool {
+    image.starts_with(b"\x7fELF")
+}
+
+/// 经过校验的 ELF64 RISC-V 可执行文件。
+pub(crate) struct Elf<'a> {
+    image: &'a [u8],
+    entry: usize,
+    phoff: usize,
+    phnum: usize,
+}
+
+/// 可加载段。
+pub(crate) struct Segment {
+    /// 虚地址。
+    pub vaddr: usize,
+    /// 内存大小。
+    pub memsz: usize,
+    /// 在文件中的范围。
+    pub file: Range<usize>,
+    /// 段权限，`PF_*` 的组合。
+    pub flags: u32,
+}
+
+impl<'a> Elf<'a> {
+    /// 解析并校验 ELF 头和所有程序头。
+    pub fn parse(image: &'a [u8]) -> Result<Self, ElfError> {
+        if image.len() < EHDR_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if !is_elf(image) {
+            return Err(ElfError::BadMagic);
+        }
+        if image[4] != ELFCLASS64 {
+            return Err(ElfError::NotElf64);
+        }
+        if image[5] != ELFDATA2LSB {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if image[6] != EV_CURRENT {
+            return Err(ElfError::BadVersion);
+        }
+        if read_u16(image, EI_NIDENT + 2) != EM_RISCV {
+            return Err(ElfError::NotRiscV);
+        }
+        if read_u16(image, EI_NIDENT) != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(image, 54) as usize != PHDR_SIZE {
+            return Err(ElfError::BadProgramHeader);
+        }
+        let elf = Self {
+            image,
+            entry: read_u64(image, 24) as _,
+            phoff: read_u64(image, 32) as _,
+            phnum: read_u16(image, 56) as _,
+        };
+        match elf.phoff.checked_add(elf.phnum * PHDR_SIZE) {
+            Some(end) if end <= image.len() => {}
+            _ => return Err(ElfError::Truncated),
+        }
+        let mut loadable = 0;
+        for (i, seg) in elf.segments() {
+            loadable += 1;
+            if seg.file.start > seg.file.end || seg.file.end > image.len() {
+                return Err(ElfError::SegmentOutOfFile(i));
+            }
+            if seg.file.len() > seg.memsz {
+                return Err(ElfError::FileSizeExceedsMemSize(i));
+            }
+            if seg.vaddr.checked_add(seg.memsz).is_none() {
+                return Err(ElfError::SizeOverflow(i));
+            }
+            if seg.flags & (PF_R | PF_W | PF_X) == 0 {
+                return Err(ElfError::NoPermission(i));
+            }
+            let bytes = seg.vaddr..seg.vaddr + seg.memsz;
+            if elf
+                .segments()
+                .take_while(|(j, _)| *j < i)
+                .any(|(_, other)| intersects(&(other.vaddr..other.vaddr + other.memsz), &bytes))
+            {
+                return Err(ElfError::Overlap(i));
+            }
+        }
+        if loadable == 0 {
+            Err(ElfError::NoLoadableSegment)
+        } else {
+            Ok(elf)
+        }
+    }
+
+    /// 遍历可加载段，同时给出程序头序号。
+    pub fn segments(&self) -> impl Iterator<Item = (usize, Segment)> + '_ {
+        (0..self.phnum).filter_map(move |i| {
+            let ph = self.phoff + i * PHDR_SIZE;
+            if read_u32(self.image, ph) != PT_LOAD {
+                return None;
+            }
+            let offset = read_u64(self.image, ph + 8) as usize;
+            let filesz = read_u64(self.image, ph + 32) as usize;
+            Some((
+                i,
+                Segment {
+                    vaddr: read_u64(self.image, ph + 16) as _,
+                    memsz: read_u64(self.image, ph + 40) as _,
+                    file: offset..offset.saturating_add(filesz),
+                    flags: read_u32(self.image, ph + 4),
+                },
+            ))
+        })
+    }
+
+    /// 加载所有段需要的页数。
+    pub fn pages<Meta: VmMeta>(&self) -> usize {
+        self.segments()
+            .map(|(_, seg)| page_range::<Meta>(&seg))
+            .map(|range| range.end.val() - range.start.val())
+            .sum()
+    }
+
+    /// 把所有可加载段复制到新分配的页中并映射到 `space`，页帧属于 `space`。
+    ///
+    /// 返回入口虚地址。`user` 决定段是否映射为用户页。
+    pub fn load<M: PageManager<Meta>, Meta: VmMeta>(
+        &self,
+        space: &mut AddressSpace<Meta, M>,
+        user: bool,
+    ) -> Result<usize, ElfError> {
+        let page_mask = (1 << Meta::PAGE_BITS) - 1;
+        // 先检查所有段，失败时不修改地址空间
+        for (i, seg) in self.segments() {
+            if seg.vaddr & page_mask != seg.file.start & page_mask {
+                return Err(ElfError::Misaligned(i));
+            }
+            let range = page_range::<Meta>(&seg);
+            if space.overlaps(&range)
+                || self
+                    .segments()
+                    .filter(|(j, _)| *j < i)
+                    .any(|(_, other)| intersects(&page_range::<Meta>(&other), &range))
+            {
+                return Err(ElfError::Overlap(i));
+            }
+        }
+        for (_, seg) in self.segments() {
+            let range = page_range::<Meta>(&seg);
+            let pages = range.end.val() - range.start.val();
+            let ppn = space.manager().allocate(VmFlags::VALID, pages).ppn();
+            let base = space.manager().p_to_v::<u8>(ppn).as_ptr();
+            unsafe {
+                let len = pages << Meta::PAGE_BITS;
+                let head = seg.vaddr & page_mask;
+                core::ptr::write_bytes(base, 0, len);
+                core::ptr::copy_nonoverlapping(
+                    self.image[seg.file.clone()].as_ptr(),
+                    base.add(head),
+                    seg.file.len(),
+                );
+            }
+            space.map_owned(range, ppn, flags(seg.flags, user), PagePolicy::Huge);
+        }
+        Ok(self.entry)
+    }
+}
+
+/// 段覆盖的虚页范围。
+fn page_range<Meta: VmMeta>(seg: &Segment) -> Range<VPN<Meta>> {
+    VAddr::<Meta>::new(seg.vaddr).floor()..VAddr::<Meta>::new(seg.vaddr + seg.memsz).ceil()
+}
+
+#[inline]
+fn intersects<T: Ord>(a: &Range<T>, b: &Range<T>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// 由段权限确定页表项标志。RISC-V 不允许只写页，可写的段总是可读的。
+fn flags<Meta: VmMeta>(p_flags: u32, user: bool) -> VmFlags<Meta> {
+    let x = p_flags & PF_X != 0;
+    let w = p_flags & PF_W != 0;
+    match (user, x, w) {
+        (false, false, false) => VmFlags::build_from_str("DA____RV"),
+        (false, false, true) => VmFlags::build_from_str("DA___WRV"),
+        (false, true, false) => VmFlags::build_from_str("DA__X_RV"),
+        (false, true, true) => VmFlags::build_from_str("DA__XWRV"),
+        (true, false, false) => VmFlags::build_from_str("DA_U__RV"),
+        (true, false, true) => VmFlags::build_from_str("DA_U_WRV"),
+        (true, true, false) => VmFlags::build_from_str("DA_UX_RV"),
+        (true, true, true) => VmFlags::build_from_str("DA_UXWRV"),
+    }
+}
+
+#[inline]
+fn read_u16(image: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([image[at], image[at + 1]])
+}
+
+#[inline]
+fn read_u32(image: &[u8], at: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&image[at..][..4]);
+    u32::from_le_bytes(bytes)
+}
+
+#[inline]
+fn read_u64(image: &[u8], at: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&image[at..][..8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// 可加载段 `(vaddr, offset, filesz, memsz)`，权限为 `RX`。
+    type Load = (u64, u64, u64, u64);
+
+    /// 构造只有程序头的 ELF64 RISC-V 可执行文件，文件总长 `len`。
+    fn image(loads: &[Load], len: usize) -> Vec<u8> {
+        let phnum = loads.len();
+        let mut image = Vec::new();
+        image.extend_from_slice(b"\x7fELF");
+        image.extend_from_slice(&[ELFCLASS64, ELFDATA2LSB, EV_CURRENT]);
+        image.resize(EI_NIDENT, 0);
+        image.extend_from_slice(&ET_EXEC.to_le_bytes());
+        image.extend_from_slice(&EM_RISCV.to_le_bytes());
+        image.extend_from_slice(&1u32.to_le_bytes());
+        image.extend_from_slice(&0x1000u64.to_le_bytes()); // e_entry
+        image.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes()); // e_phoff
+        image.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+        image.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        image.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+        image.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        image.extend_from_slice(&(phnum as u16).to_le_bytes());
+        image.resize(EHDR_SIZE, 0);
+        for &(vaddr, offset, filesz, memsz) in loads {
+            image.extend_from_slice(&PT_LOAD.to_le_bytes());
+            image.extend_from_slice(&(PF_R | PF_X).to_le_bytes());
+            for field in [offset, vaddr, vaddr, filesz, memsz, 0x1000] {
+                image.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+        image.resize(len.max(image.len()), 0);
+        image
+    }
+
+    fn parse(image: &[u8]) -> Result<(), ElfError> {
+        Elf::parse(image).map(|_| ())
+    }
+
+    #[test]
+    fn valid() {
+        let image = image(&[(0x1000, 0, 0x100, 0x2000)], 0x100);
+        let elf = Elf::parse(&image).unwrap();
+        assert_eq!(elf.entry, 0x1000);
+        assert_eq!(elf.segments().count(), 1);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut image = image(&[(0x1000, 0, 0x100, 0x100)], 0x100);
+        image[1] = b'X';
+        assert_eq!(parse(&image), Err(ElfError::BadMagic));
+        assert_eq!(parse(&image[..16]), Err(ElfError::Truncated));
+    }
+
+    #[test]
+    fn wrong_class() {
+        let mut image = image(&[(0x1000, 0, 0x100, 0x100)], 0x100);
+        image[4] = 1;
+        assert_eq!(parse(&image), Err(ElfError::NotElf64));
+    }
+
+    #[test]
+    fn wrong_machine() {
+        let mut image = image(&[(0x1000, 0, 0x100, 0x100)], 0x100);
+        image[EI_NIDENT + 2..][..2].copy_from_slice(&62u16.to_le_bytes());
+        assert_eq!(parse(&image), Err(ElfError::NotRiscV));
+    }
+
+    #[test]
+    fn segment_out_of_file() {
+        let image = image(&[(0x1000, 0x80, 0x100, 0x100)], 0x100);
+        assert_eq!(parse(&image), Err(ElfError::SegmentOutOfFile(0)));
+    }
+
+    #[test]
+    fn memsz_less_than_filesz() {
+        let image = image(&[(0x1000, 0, 0x100, 0x80)], 0x100);
+        assert_eq!(parse(&image), Err(ElfError::FileSizeExceedsMemSize(0)));
+    }
+
+    #[test]
+    fn overlapping_segments() {
+        let loads = [(0x1000, 0, 0x100, 0x1000), (0x1800, 0, 0x100, 0x100)];
+        assert_eq!(parse(&image(&loads, 0x100)), Err(ElfError::Overlap(1)));
+        let loads = [(0x1000, 0, 0x100, 0x1000), (0x2000, 0, 0x100, 0x100)];
+        assert_eq!(parse(&image(&loads, 0x100)), Ok(()));
+    }
+}
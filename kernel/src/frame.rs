@@ -0,0 +1,176 @@
+﻿//! 页帧元数据。
+//!
+//! 页帧分配器管理的物理内存中，每个页帧有一个 32 位的元数据，低 16 位是引用计数，高位是标志。
+//! 只有标记为 [`OWNED`] 的页帧属于页管理器，计数归零时还给页帧分配器；
+//! 其他页帧（内核镜像、保留区、直接从 [`GLOBAL`] 分配的页）不计数，释放时忽略。
+
+use crate::{
+    non_null,
+    page::{GLOBAL, MAX_MEMORY},
+    LAYOUT,
+};
+use core::{
+    alloc::Layout,
+    ops::Range,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+use page_table::{MmuMeta, Sv39};
+use spin::Once;
+
+/// 引用计数的掩码。
+const COUNT: u32 = 0xffff;
+
+/// 页帧由页管理器分配。
+const OWNED: u32 = 1 << 16;
+
+/// 页大小的位数。
+const PAGE_BITS: usize = Sv39::PAGE_BITS;
+
+/// 一段连续物理内存的元数据表。
+struct Bank {
+    /// 第一个页帧的物理页号。
+    base: usize,
+    frames: &'static [AtomicU32],
+}
+
+/// 元数据表，每段可用内存一张，不覆盖段之间的空洞。
+struct Table {
+    banks: [Bank; MAX_MEMORY],
+    len: usize,
+}
+
+static TABLE: Once<Table> = Once::new();
+
+/// 页管理器从页帧分配器取走、还没有还回去的页帧数。
+static IN_USE: AtomicUsize = AtomicUsize::new(0);
+
+/// 找到物理页号 `ppn` 的元数据。
+fn meta(ppn: usize) -> &'static AtomicU32 {
+    let table = TABLE.get().expect("frame table is not initialized");
+    table.banks[..table.len]
+        .iter()
+        .find_map(|bank| ppn.checked_sub(bank.base).and_then(|i| bank.frames.get(i)))
+        .unwrap_or_else(|| panic!("frame {ppn:#x} is out of the frame table"))
+}
+
+/// 为物理地址范围 `memory` 中的每一段建立元数据表，表本身从页帧分配器分配。
+pub(crate) fn init(memory: &[Range<usize>]) {
+    const ALIGN: usize = (1 << PAGE_BITS) - 1;
+    const EMPTY: Bank = Bank {
+        base: 0,
+        frames: &[],
+    };
+    let mut banks = [EMPTY; MAX_MEMORY];
+    for (bank, range) in banks.iter_mut().zip(memory) {
+        let base = range.start >> PAGE_BITS;
+        let len = ((range.end + ALIGN) >> PAGE_BITS) - base;
+        let size = (len * core::mem::size_of::<AtomicU32>() + ALIGN) & !ALIGN;
+        let frames = unsafe {
+            let (ptr, _) = GLOBAL
+                .allocate_layout::<AtomicU32>(Layout::from_size_align_unchecked(size, ALIGN + 1))
+                .unwrap();
+            core::ptr::write_bytes(ptr.as_ptr(), 0, len);
+            core::slice::from_raw_parts(ptr.as_ptr(), len)
+        };
+        log::info!(
+            "frame table: {:#x}..{:#x}, {len} frames, {} KiB",
+            range.start,
+            range.end,
+            size >> 10,
+        );
+        *bank = Bank { base, frames };
+    }
+    let len = memory.len().min(MAX_MEMORY);
+    TABLE.call_once(|| Table { banks, len });
+}
+
+/// 分配 `len` 个连续页帧，引用计数都为 1，返回第一个页帧的物理页号。
+pub(crate) fn allocate(len: usize) -> usize {
+    let pages = len.next_power_of_two();
+    let ptr = unsafe {
+        GLOBAL
+            .allocate_layout::<u8>(Layout::from_size_align_unchecked(
+                pages << PAGE_BITS,
+                1 << PAGE_BITS,
+            ))
+            .unwrap()
+            .0
+    };
+    let ppn = unsafe { LAYOUT.v_to_p(ptr.as_ptr() as _) } >> PAGE_BITS;
+    // 按 2 的幂分配，多出的页立即还回去
+    for ppn in ppn + len..ppn + pages {
+        free(ppn);
+    }
+    for ppn in ppn..ppn + len {
+        let old = meta(ppn).swap(OWNED | 1, Ordering::AcqRel);
+        debug_assert_eq!(old, 0, "frame {ppn:#x} allocated twice");
+    }
+    IN_USE.fetch_add(len, Ordering::Relaxed);
+    ppn
+}
+
+/// 增加从 `ppn` 开始的 `len` 个页帧的引用计数，不属于页管理器的页帧不变。
+pub(crate) fn acquire(ppn: usize, len: usize) {
+    for ppn in ppn..ppn + len {
+        let _ = meta(ppn).fetch_update(Ordering::AcqRel, Ordering::Acquire, |meta| {
+            if meta & OWNED == 0 {
+                None
+            } else if meta & COUNT == COUNT {
+                panic!("frame {ppn:#x} is shared too many times")
+            } else {
+                Some(meta + 1)
+            }
+        });
+    }
+}
+
+/// 减少从 `ppn` 开始的 `len` 个页帧的引用计数，归零的页帧还给页帧分配器。
+pub(crate) fn release(ppn: usize, len: usize) {
+    for ppn in ppn..ppn + len {
+        let old = meta(ppn).fetch_update(Ordering::AcqRel, Ordering::Acquire, |meta| {
+            if meta & OWNED == 0 {
+                None
+            } else if meta & COUNT == 1 {
+                Some(0)
+            } else {
+                Some(meta - 1)
+            }
+        });
+        if old.map_or(false, |meta| meta & COUNT == 1) {
+            free(ppn);
+            IN_USE.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// 判断从 `ppn` 开始的 `len` 个页帧是否都属于页管理器且只被引用一次。
+pub(crate) fn is_exclusive(ppn: usize, len: usize) -> bool {
+    (ppn..ppn + len).all(|ppn| meta(ppn).load(Ordering::Acquire) == OWNED | 1)
+}
+
+/// 页管理器正在使用的页帧数。
+pub(crate) fn in_use() -> usize {
+    IN_USE.load(Ordering::Relaxed)
+}
+
+/// 把一个页帧还给页帧分配器。
+fn free(ppn: usize) {
+    unsafe {
+        GLOBAL.deallocate_layout(
+            non_null::<u8>(LAYOUT.p_to_v(ppn << PAGE_BITS)),
+            Layout::from_size_align_unchecked(1 << PAGE_BITS, 1 << PAGE_BITS),
+        )
+    };
+}
+
+bench!(share_exclude, |b| {
+    use crate::{space::PageManager, Global};
+    use page_table::VmFlags;
+    b.iter(|| {
+        let pte = PageManager::<Sv39>::allocate(&mut Global, VmFlags::VALID, 1);
+        let (shared, private) = Global.share(pte, 1);
+        let private = Global.exclude(private, 1);
+        Global.deallocate(shared, 1);
+        Global.deallocate(private, 1);
+    })
+});
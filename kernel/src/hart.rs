@@ -0,0 +1,202 @@
+﻿use crate::{
+    boot::BootPageTable,
+    device_tree,
+    layout::KernelLayout,
+    param::Param,
+    trap::{self, Cause, TrapContext},
+    LAYOUT,
+};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use riscv::register::{satp, sie, sip};
+
+param! {
+    /// 最多启动的硬件线程数量，包括主核。
+    pub(crate) static SMP: Param<usize> =
+        Param::new("smp", "最多启动的硬件线程数量", KernelLayout::MAX_HARTS);
+}
+
+/// 已经进入内核地址空间的硬件线程数量。
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// 已经进入内核地址空间的硬件线程位图。
+static ONLINE_MASK: AtomicUsize = AtomicUsize::new(0);
+
+/// 主核要求其他硬件线程停止。
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// 当前硬件线程编号，入口处保存在 `tp` 中。
+#[inline]
+pub(crate) fn id() -> usize {
+    let id: usize;
+    unsafe { core::arch::asm!("mv {}, tp", out(reg) id) };
+    id
+}
+
+/// 除当前硬件线程以外已经上线的硬件线程位图，修改页表后需要通知它们刷新地址转换缓存。
+#[inline]
+pub(crate) fn others() -> usize {
+    ONLINE_MASK.load(Ordering::Acquire) & !(1 << id())
+}
+
+/// 从设备树 `/cpus` 节点枚举硬件线程。
+///
+/// 返回硬件线程编号的位图，`status = "disabled"` 的节点和编号不小于 [`KernelLayout::MAX_HARTS`] 的硬件线程被忽略。
+pub(crate) fn enumerate() -> usize {
+    use dtb_walker::{DtbObj, Property, WalkOperation::*};
+    let mut harts = 0usize;
+    // 当前 cpu 节点的编号和状态，`reg` 和 `status` 的顺序不定，离开节点时才能确定
+    let mut node = Cpu::default();
+    device_tree::get().dtb().walk(|path, obj| match obj {
+        DtbObj::SubNode { name } => {
+            node.commit(&mut harts);
+            if path.is_root() && name.starts_with("cpus") {
+                StepInto
+            } else if path.name().starts_with("cpus") && name.starts_with("cpu@") {
+                StepInto
+            } else {
+                StepOver
+            }
+        }
+        DtbObj::Property(Property::Reg(reg)) if path.name().starts_with("cpu@") => {
+            for segment in reg {
+                if segment.start < KernelLayout::MAX_HARTS {
+                    node.harts |= 1 << segment.start;
+                } else {
+                    log::warn!("hart {} ignored: no boot stack", segment.start);
+                }
+            }
+            StepOver
+        }
+        DtbObj::Property(Property::General { name, value })
+            if path.name().starts_with("cpu@") && name.starts_with("status") =>
+        {
+            node.disabled = value.starts_with(b"disabled");
+            StepOver
+        }
+        DtbObj::Property(_) => StepOver,
+    });
+    node.commit(&mut harts);
+    harts
+}
+
+/// 正在遍历的 cpu 节点。
+#[derive(Default)]
+struct Cpu {
+    harts: usize,
+    disabled: bool,
+}
+
+impl Cpu {
+    /// 离开节点，未禁用的硬件线程加入 `harts`。
+    fn commit(&mut self, harts: &mut usize) {
+        if !self.disabled {
+            *harts |= self.harts;
+        } else if self.harts != 0 {
+            log::info!("harts {:#x} disabled", self.harts);
+        }
+        *self = Self::default();
+    }
+}
+
+/// 启动 `harts` 中除 `hartid` 以外的所有硬件线程，并等待它们进入内核地址空间。
+///
+/// 副核先使用启动页表跃迁到高地址，再切换到当前的内核地址空间，
+/// 所以必须在回收启动页表之前调用。
+pub(crate) fn boot_secondary(hartid: usize, harts: usize) {
+    let entry = unsafe { LAYOUT.v_to_p(_secondary_start as usize) };
+    let satp = satp::read().bits();
+    trap::register(Cause::SUPERVISOR_SOFT, software);
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+    ONLINE_MASK.fetch_or(1 << hartid, Ordering::AcqRel);
+    let mut expected = 1;
+    for id in (0..KernelLayout::MAX_HARTS)
+        .filter(|i| harts & (1 << i) != 0 && *i != hartid)
+        .take(SMP.get().saturating_sub(1))
+    {
+        let ret = sbi_rt::hart_start(id, entry, satp);
+        if ret.error == 0 {
+            expected += 1;
+        } else {
+            log::warn!("failed to start hart {id}: {ret:?}");
+        }
+    }
+    while ONLINE.load(Ordering::Acquire) < expected {
+        core::hint::spin_loop();
+    }
+    log::info!("{expected} hart(s) online");
+}
+
+/// 停止除当前硬件线程以外的所有硬件线程，并等待 SBI 报告它们已经停止。
+///
+/// 停止的硬件线程不再访问内核的内存，之后可以由下一阶段通过 `hart_start` 重新启动。
+pub(crate) fn stop_others() {
+    /// HSM 扩展中硬件线程已停止的状态。
+    const STOPPED: usize = 1;
+    let others = others();
+    if others == 0 {
+        return;
+    }
+    STOP.store(true, Ordering::Release);
+    let ret = sbi_rt::send_ipi(others, 0);
+    assert_eq!(ret.error, 0, "failed to send ipi: {ret:?}");
+    while ONLINE_MASK.load(Ordering::Acquire) & others != 0 {
+        core::hint::spin_loop();
+    }
+    // 副核清除在线标记之后还在执行内核代码，直到 SBI 停止它
+    for id in (0..KernelLayout::MAX_HARTS).filter(|i| others & (1 << i) != 0) {
+        while sbi_rt::hart_get_status(id).value != STOPPED {
+            core::hint::spin_loop();
+        }
+    }
+    log::info!("harts {others:#x} stopped");
+}
+
+/// 副核在物理地址空间的 Rust 入口。
+///
+/// `satp` 是主核内核地址空间的 satp 值，由 `hart_start` 的 `opaque` 参数传入。
+extern "C" fn rust_main_secondary(hartid: usize, satp: usize) -> ! {
+    // 复用主核建立的启动页表上链接位置
+    unsafe { BootPageTable(LAYOUT.v_to_p(LAYOUT.boot_pt_root())).enable(&LAYOUT, satp) };
+    // 切换到内核地址空间
+    unsafe {
+        core::arch::asm!("csrw satp, {0}", in(reg) satp);
+        riscv::asm::sfence_vma_all();
+    }
+    hart_main(hartid)
+}
+
+/// 每个副核的入口。
+fn hart_main(hartid: usize) -> ! {
+    trap::init();
+    crate::timer::init_hart();
+    unsafe { sie::set_ssoft() };
+    log::info!("hart {hartid} online");
+    ONLINE_MASK.fetch_or(1 << hartid, Ordering::AcqRel);
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+    loop {
+        unsafe { riscv::asm::wfi() };
+    }
+}
+
+/// 核间中断，主核通过它要求副核停止。
+fn software(_ctx: &mut TrapContext) {
+    unsafe { sip::clear_ssoft() };
+    if STOP.load(Ordering::Acquire) {
+        ONLINE.fetch_sub(1, Ordering::AcqRel);
+        ONLINE_MASK.fetch_and(!(1 << id()), Ordering::AcqRel);
+        let ret = sbi_rt::hart_stop();
+        panic!("failed to stop hart {}: {ret:?}", id());
+    }
+}
+
+/// 副核入口，经 [`entry`](crate::entry) 设置启动栈后进入 [`rust_main_secondary`]。
+#[naked]
+unsafe extern "C" fn _secondary_start(_hartid: usize, _satp: usize) -> ! {
+    core::arch::asm!(
+        "   lla  t2, {main}",
+        "   j    {entry}",
+        entry = sym crate::entry,
+        main  = sym rust_main_secondary,
+        options(noreturn),
+    )
+}
@@ -3,12 +3,16 @@ use alloc::alloc::handle_alloc_error;
 use core::{
     alloc::{GlobalAlloc, Layout},
     ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 use customizable_buddy::{BuddyAllocator, LinkedListBuddy, UsizeBuddy};
 
 /// 内核堆分配器。
 static mut HEAP: BuddyAllocator<20, UsizeBuddy, LinkedListBuddy> = BuddyAllocator::new();
 
+/// 堆从页帧分配器取走的字节数，这些内存不再归还。
+static GROWN: AtomicUsize = AtomicUsize::new(0);
+
 struct Heap;
 
 #[global_allocator]
@@ -19,6 +23,11 @@ pub(crate) fn init_heap(start: usize) {
     unsafe { HEAP.init(3, non_null::<u8>(start)) };
 }
 
+/// 堆从页帧分配器取走的字节数。
+pub(crate) fn grown() -> usize {
+    GROWN.load(Ordering::Relaxed)
+}
+
 unsafe impl GlobalAlloc for Heap {
     #[inline]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
@@ -27,6 +36,7 @@ unsafe impl GlobalAlloc for Heap {
         } else if let Ok((ptr, size)) = GLOBAL.allocate_layout::<u8>(
             Layout::from_size_align_unchecked(layout.size().next_power_of_two(), layout.align()),
         ) {
+            GROWN.fetch_add(size, Ordering::Relaxed);
             HEAP.transfer(ptr, size);
             HEAP.allocate_layout::<u8>(layout).unwrap().0.as_ptr()
         } else {
@@ -39,3 +49,5 @@ unsafe impl GlobalAlloc for Heap {
         HEAP.deallocate_layout(NonNull::new(ptr).unwrap(), layout)
     }
 }
+
+bench!(box_alloc, |b| b.iter(|| alloc::boxed::Box::new(0usize)));
@@ -1,10 +1,20 @@
-﻿use linker::MemInfo;
+﻿use crate::boot;
+use core::ops::Range;
+use linker::MemInfo;
 use page_table::{MmuMeta, Sv39};
 
+/// 构建时与链接脚本一起生成的常量，不一定都用到。
+#[allow(unused)]
+mod script {
+    include!(concat!(env!("OUT_DIR"), "/linker.rs"));
+}
+
 /// 内核内存布局。
 ///
-/// - 启动时：内核 | 启动栈 | 启动页表 | 动态区
-/// - 启动后：内核 | 启动栈 | 动态区
+/// - 启动时：内核 | 启动栈 × [`MAX_HARTS`](Self::MAX_HARTS) | 启动页表 | 动态区
+/// - 启动后：内核 | 启动栈 × [`MAX_HARTS`](Self::MAX_HARTS) | 动态区
+///
+/// 每个硬件线程使用编号对应的启动栈，`hartid` 号硬件线程的栈顶位于 `_end + (hartid + 1) * BOOT_STACK_SIZE`。
 pub struct KernelLayout {
     /// 链接时确定的符号。
     linked: MemInfo,
@@ -16,14 +26,22 @@ impl KernelLayout {
     /// 启动栈容量。
     pub const BOOT_STACK_SIZE: usize = 4096 * 4;
 
+    /// 支持的最大硬件线程数量。
+    ///
+    /// 编号不小于这个值的硬件线程没有启动栈，不会被启动。
+    pub const MAX_HARTS: usize = 8;
+
+    /// 启动页表占用的页数，见 [`boot::BOOT_PT_PAGES`]。
+    pub const BOOT_PT_PAGES: usize = boot::BOOT_PT_PAGES;
+
     pub const INIT: Self = Self {
         linked: MemInfo::INIT,
         top: usize::MAX,
     };
 
-    /// 物理地址动态定位。
-    pub unsafe fn locate(&mut self) {
-        self.linked = MemInfo::locate();
+    /// 物理地址动态定位，`offset` 是选中的分页模式的线性区偏移。
+    pub unsafe fn locate(&mut self, offset: usize) {
+        self.linked = MemInfo::locate(script::START, offset);
     }
 
     /// 设置线性地址结束位置。
@@ -42,10 +60,28 @@ impl KernelLayout {
         core::slice::from_raw_parts_mut(bss as _, end - bss).fill(0u8);
     }
 
-    /// 启动页表根节点：启动栈之后的第一个页
+    /// 启动页表根节点：所有启动栈之后的第一个页
     pub fn boot_pt_root(&self) -> usize {
         const ALIGN: usize = (1 << Sv39::PAGE_BITS) - 1;
-        (self.linked.end + Self::BOOT_STACK_SIZE + ALIGN) & !ALIGN
+        (self.linked.end + Self::BOOT_STACK_SIZE * Self::MAX_HARTS + ALIGN) & !ALIGN
+    }
+
+    /// 启动页表结束位置，也是动态区的起始位置。
+    pub fn boot_pt_end(&self) -> usize {
+        self.boot_pt_root() + (Self::BOOT_PT_PAGES << Sv39::PAGE_BITS)
+    }
+
+    /// 内核是否按链接脚本的页大小对齐地加载，并且内核镜像、启动栈和启动页表都在线性区内。
+    ///
+    /// 各段按这个页大小对齐，加载位置也对齐才能按段设置权限。
+    /// 启动页表按加载位置的对齐选择 4 KiB、2 MiB 或 1 GiB 的页映射内核，见 [`boot::BOOT_PT_PAGES`]。
+    pub fn fits(&self) -> bool {
+        const ALIGN: usize = script::PAGE_SIZE - 1;
+        let p_start = self.v_to_p(self.linked.start);
+        let p_end = self.v_to_p(self.linked.end)
+            + Self::BOOT_STACK_SIZE * Self::MAX_HARTS
+            + ((Self::BOOT_PT_PAGES + 1) << Sv39::PAGE_BITS);
+        p_start & ALIGN == 0 && p_end <= self.offset().wrapping_neg()
     }
 
     /// 线性区虚地址相对物理地址的偏移。
@@ -53,6 +89,11 @@ impl KernelLayout {
         self.linked.offset
     }
 
+    /// 内核运行位置相对链接位置的偏移。
+    pub const fn bias(&self) -> usize {
+        self.linked.bias()
+    }
+
     /// 物理地址转换为线性区虚地址。
     pub const fn p_to_v(&self, paddr: usize) -> usize {
         paddr + self.linked.offset
@@ -60,7 +101,7 @@ impl KernelLayout {
 
     /// 线性区虚地址转换为物理地址。
     pub const fn v_to_p(&self, vaddr: usize) -> usize {
-        vaddr - self.linked.offset
+        vaddr.wrapping_sub(self.linked.offset)
     }
 
     /// 内核起始地址。
@@ -68,6 +109,16 @@ impl KernelLayout {
         self.linked.start
     }
 
+    /// 内核代码段，可读可执行。
+    pub const fn text(&self) -> Range<usize> {
+        self.linked.start..self.linked.rodata
+    }
+
+    /// 内核只读数据段，包括基准测试表和重定位表。
+    pub const fn rodata(&self) -> Range<usize> {
+        self.linked.rodata..self.linked.data
+    }
+
     /// 线性区结束位置。
     pub const fn top(&self) -> usize {
         self.top
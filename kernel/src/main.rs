@@ -1,93 +1,198 @@
 #![no_std]
 #![no_main]
-#![feature(naked_functions, asm_sym, asm_const)]
+#![feature(naked_functions, asm_sym, asm_const, fn_align)]
 #![feature(default_alloc_error_handler)]
 #![deny(warnings)]
 
+#[macro_use]
+mod param;
+#[macro_use]
+mod bench;
+mod asid;
 mod boot;
+mod device_tree;
+mod elf;
+mod frame;
+mod hart;
 mod heap;
 mod layout;
 mod page;
+mod payload;
+mod pmu;
+mod reloc;
 mod space;
+mod timer;
+mod trap;
+mod user;
 
 #[macro_use]
 extern crate console;
 extern crate alloc;
 
-use boot::BootPageTable;
-use core::{alloc::Layout, ptr::NonNull};
+use alloc::boxed::Box;
+use boot::{BootPageTable, PagingMode};
+use core::ptr::NonNull;
+use device_tree::DeviceTree;
 use layout::KernelLayout;
 use page::GLOBAL;
-use page_table::{MmuMeta, Pte, Sv39, VmFlags, PPN, VPN};
+use page_table::{Pte, Sv39, Sv48, Sv57, VmFlags, VmMeta, PPN, VPN};
+use param::{LogLevel, Param};
 use riscv::register::satp;
 use sbi_rt::*;
 use space::{AddressSpace, PageManager};
+use spin::Mutex;
+use trap::Cause;
 
 static mut LAYOUT: KernelLayout = KernelLayout::INIT;
 
-extern "C" fn rust_main(_hartid: usize, dtb_addr: usize) -> ! {
+param! {
+    /// 日志级别，默认值来自构建时的 `LOG` 环境变量。
+    static LOG: Param<LogLevel> = Param::new(
+        "log",
+        "日志级别",
+        LogLevel(match option_env!("LOG") {
+            Some(level) => level,
+            None => "trace",
+        }),
+    );
+}
+
+extern "C" fn rust_main(hartid: usize, dtb_addr: usize) -> ! {
+    // 探测分页模式
+    let mode = unsafe { boot::probe() };
     // 收集内存信息
-    unsafe { LAYOUT.locate() };
+    unsafe { LAYOUT.locate(boot::linear_offset(mode)) };
+    // 加载位置不能放进线性区时无法启动，此时还不能打印
+    if unsafe { !LAYOUT.fits() } {
+        system_reset(RESET_TYPE_SHUTDOWN, RESET_REASON_SYSTEM_FAILURE);
+        unreachable!()
+    }
+    // 重定位
+    let relocated = unsafe { reloc::relocate(&LAYOUT) };
     // 上链接位置
-    let _ = unsafe {
-        BootPageTable(non_null(LAYOUT.v_to_p(LAYOUT.boot_pt_root()))).launch(&LAYOUT);
-    };
+    unsafe { BootPageTable(LAYOUT.v_to_p(LAYOUT.boot_pt_root())).launch(&LAYOUT, mode) };
     // FIXME 强行通过虚地址访问静态变量。不这么写编译器没法知道这个变量有两个地址。
     let info = unsafe { &mut *(LAYOUT.p_to_v((&LAYOUT) as *const _ as _) as *mut KernelLayout) };
     // 清零 .bss
     unsafe { info.zero_bss() };
     // 确认打印可用
     console::init_console(&Console);
-    console::set_log_level(option_env!("LOG"));
+    console::set_log_level(Some(LOG.get().0));
+    // 换用完整的陷入处理
+    trap::init();
     console::test_log();
+    // 解析内核参数
+    let dt = match unsafe { DeviceTree::from_raw(info.p_to_v(dtb_addr) as _) } {
+        Ok(dt) => dt,
+        Err(e) => panic!("invalid device tree at {dtb_addr:#x}: {e:?}"),
+    };
+    param::parse(&dt);
+    console::set_log_level(Some(LOG.get().0));
+    log::info!("paging mode: {mode:?}");
+    log::debug!(
+        "kernel at {:#x}, {relocated} relocation(s) applied",
+        info.start()
+    );
     // 初始化页分配
-    info.set_top(page::init_global(info, dtb_addr));
+    info.set_top(page::init_global(info, dt));
     // 初始化堆分配
     heap::init_heap(info.start());
+    // 按分页模式进入内核
+    match mode {
+        satp::Mode::Sv57 => kernel_main::<Sv57>(hartid, info),
+        satp::Mode::Sv48 => kernel_main::<Sv48>(hartid, info),
+        _ => kernel_main::<Sv39>(hartid, info),
+    }
+}
+
+/// 以 `Meta` 分页模式建立内核地址空间并运行。
+fn kernel_main<Meta: PagingMode>(hartid: usize, info: &KernelLayout) -> ! {
     // 建立内核地址空间
-    let mut kernel = AddressSpace::<Sv39, Global>::new(Global);
+    let mut kernel = AddressSpace::<Meta, Global>::new(Global);
     kernel.kernel(VmFlags::build_from_str("DAG_XWRV"));
-    unsafe { satp::set(satp::Mode::Sv39, 0, kernel.root_ppn().val()) };
+    asid::init();
+    kernel.activate();
+    kernel.register_kernel();
     println!("{kernel:?}");
+    // 设置陷入处理
+    trap::register(Cause::BREAKPOINT, trap::breakpoint);
+    trap::register(Cause::INSTRUCTION_PAGE_FAULT, trap::page_fault);
+    trap::register(Cause::LOAD_PAGE_FAULT, trap::page_fault);
+    trap::register(Cause::STORE_PAGE_FAULT, trap::page_fault);
+    // 此后内核地址空间只通过锁访问
+    let kernel: &'static Mutex<_> = Box::leak(Box::new(Mutex::new(kernel)));
+    trap::set_space(kernel);
+    user::init();
+    // 检查内核镜像不可写、线性区不可执行
+    space::wx_check::<Meta>(&kernel.lock());
+    // 初始化时钟
+    timer::init();
+    // 探测性能计数器
+    pmu::init();
+    // 检查地址空间销毁后页帧全部归还
+    if space::TEARDOWN_CHECK.get() {
+        space::teardown_check::<Meta>();
+    }
+    // 运行基准测试
+    bench::run();
+    // 启动副核
+    hart::boot_secondary(hartid, hart::enumerate());
     // 回收启动页表
-    unsafe { GLOBAL.deallocate(non_null::<u8>(info.boot_pt_root() as _), 4096) };
+    unsafe {
+        GLOBAL.transfer(
+            non_null::<u8>(info.boot_pt_root()),
+            info.boot_pt_end() - info.boot_pt_root(),
+        )
+    };
     unsafe { println!("{GLOBAL:?}") };
+    // 启动下一阶段载荷
+    if let Some(image) = payload::locate(info) {
+        payload::launch::<Meta>(hartid, info, image);
+    }
     system_reset(RESET_TYPE_SHUTDOWN, RESET_REASON_NO_REASON);
     unreachable!()
 }
 
 struct Global;
 
-impl PageManager<Sv39> for Global {
-    fn allocate(&mut self, flags: VmFlags<Sv39>, len: usize) -> Pte<Sv39> {
-        let (ptr, _) = unsafe {
-            GLOBAL.allocate_layout::<u8>(Layout::from_size_align_unchecked(
-                len << Sv39::PAGE_BITS,
-                1 << Sv39::PAGE_BITS,
-            ))
-        }
-        .unwrap();
-        flags.build_pte(self.v_to_p(ptr))
+impl<Meta: VmMeta> PageManager<Meta> for Global {
+    fn allocate(&mut self, flags: VmFlags<Meta>, len: usize) -> Pte<Meta> {
+        flags.build_pte(PPN::new(frame::allocate(len)))
     }
 
-    fn deallocate(&mut self, _pte: Pte<Sv39>, _len: usize) {
-        todo!()
+    fn deallocate(&mut self, pte: Pte<Meta>, len: usize) {
+        frame::release(pte.ppn().val(), len)
     }
 
-    fn share(&mut self, _pte: Pte<Sv39>, _len: usize) -> (Pte<Sv39>, Pte<Sv39>) {
-        todo!()
+    fn share(&mut self, pte: Pte<Meta>, len: usize) -> (Pte<Meta>, Pte<Meta>) {
+        frame::acquire(pte.ppn().val(), len);
+        (pte, pte)
     }
 
-    fn exclude(&mut self, _pte: Pte<Sv39>, _len: usize) -> Pte<Sv39> {
-        todo!()
+    fn exclude(&mut self, pte: Pte<Meta>, len: usize) -> Pte<Meta> {
+        let ppn = pte.ppn().val();
+        if frame::is_exclusive(ppn, len) {
+            return pte;
+        }
+        // 复制一份私有的页帧，再放弃对原页帧的引用
+        let copy = frame::allocate(len);
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                self.p_to_v::<u8>(PPN::new(ppn)).as_ptr(),
+                self.p_to_v::<u8>(PPN::new(copy)).as_ptr(),
+                len << Meta::PAGE_BITS,
+            )
+        };
+        frame::release(ppn, len);
+        pte.flags().build_pte(PPN::new(copy))
     }
 
-    fn p_to_v<T>(&self, ppn: PPN<Sv39>) -> NonNull<T> {
-        non_null(unsafe { LAYOUT.p_to_v(VPN::<Sv39>::new(ppn.val()).base().val()) } as _)
+    fn p_to_v<T>(&self, ppn: PPN<Meta>) -> NonNull<T> {
+        non_null(unsafe { LAYOUT.p_to_v(VPN::<Meta>::new(ppn.val()).base().val()) } as _)
     }
 
-    fn v_to_p<T>(&self, ptr: NonNull<T>) -> PPN<Sv39> {
-        PPN::new((unsafe { LAYOUT.v_to_p(ptr.as_ptr() as _) }) >> Sv39::PAGE_BITS)
+    fn v_to_p<T>(&self, ptr: NonNull<T>) -> PPN<Meta> {
+        PPN::new((unsafe { LAYOUT.v_to_p(ptr.as_ptr() as _) }) >> Meta::PAGE_BITS)
     }
 }
 
@@ -107,32 +212,56 @@ impl console::Console for Console {
     }
 }
 
-/// 检测支持的 ASID 位数。
-#[allow(unused)]
-fn asid_detect() -> usize {
-    use core::arch::asm;
-    unsafe {
-        const MASK: usize = (1 << 16) - 1;
-        let mut mask = MASK << 44;
-        asm!(
-            "csrrs zero, satp, {0}",
-            "csrr   {0}, satp",
-            inlateout(reg) mask
-        );
-        ((mask >> 44) & MASK).trailing_ones() as _
-    }
-}
-
 #[naked]
 #[no_mangle]
 #[link_section = ".text.entry"]
 unsafe extern "C" fn _start() -> ! {
     core::arch::asm!(
-        "la sp, _end + {size}",
-        "mv tp, a0",
-        "j  {main}",
-        size = const KernelLayout::BOOT_STACK_SIZE,
-        main =   sym rust_main,
+        "   lla  t2, {main}",
+        "   j    {entry}",
+        entry = sym entry,
+        main  = sym rust_main,
+        options(noreturn),
+    )
+}
+
+/// SBI 系统重置扩展号。
+const EID_SRST: usize = 0x5352_5354;
+
+/// 主核和副核共用的入口。
+///
+/// 设置早期陷入处理，按 `a0` 中的硬件线程编号选择启动栈，然后跳转到 `t2` 中的 Rust 入口。
+/// 编号超出 [`KernelLayout::MAX_HARTS`] 时没有启动栈：副核停在 `wfi`，主核无法启动，直接关机。
+#[naked]
+pub(crate) unsafe extern "C" fn entry() -> ! {
+    core::arch::asm!(
+        "   lla  t0, {trap}",
+        "   csrw stvec, t0",
+        "   li   t0, {max}",
+        "   bgeu a0, t0, 1f",
+        "   addi t0, a0, 1",
+        "   li   t1, {size}",
+        "   mul  t0, t0, t1",
+        "   lla  sp, _end",
+        "   add  sp, sp, t0",
+        "   mv   tp, a0",
+        "   jr   t2",
+        "1: lla  t0, {main}",
+        "   bne  t2, t0, 2f",
+        "   li   a0, {shutdown}",
+        "   li   a1, {failure}",
+        "   li   a6, 0",
+        "   li   a7, {srst}",
+        "   ecall",
+        "2: wfi",
+        "   j    2b",
+        max      = const KernelLayout::MAX_HARTS,
+        size     = const KernelLayout::BOOT_STACK_SIZE,
+        trap     =   sym trap::early_handler,
+        main     =   sym rust_main,
+        shutdown = const RESET_TYPE_SHUTDOWN,
+        failure  = const RESET_REASON_SYSTEM_FAILURE,
+        srst     = const EID_SRST,
         options(noreturn),
     )
 }
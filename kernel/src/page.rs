@@ -1,24 +1,160 @@
-﻿use crate::{layout::KernelLayout, non_null};
+﻿use crate::{
+    device_tree::{self, DeviceTree},
+    frame,
+    layout::KernelLayout,
+    non_null,
+    param::Param,
+    payload,
+};
+use core::{alloc::Layout, ops::Range};
 use customizable_buddy::{BuddyAllocator, LinkedListBuddy, UsizeBuddy};
 use page_table::{MmuMeta, Sv39};
 
+param! {
+    /// 交给页分配器的内存总量上限。
+    pub(crate) static MEM: Param<usize> = Param::new("mem", "使用的内存总量", usize::MAX);
+}
+
+/// 页对齐掩码。
+const ALIGN: usize = (1 << Sv39::PAGE_BITS) - 1;
+
 /// 全局页帧分配器。
 pub static mut GLOBAL: BuddyAllocator<20, UsizeBuddy, LinkedListBuddy> = BuddyAllocator::new();
 
+/// 不交给页帧分配器的物理内存区域。
+#[derive(Clone, Debug)]
+pub(crate) struct Reserved {
+    /// 物理地址范围。
+    pub range: Range<usize>,
+    /// 来源。
+    pub kind: &'static str,
+    /// 是否禁止建立映射。
+    pub no_map: bool,
+}
+
+impl Reserved {
+    const EMPTY: Self = Self {
+        range: 0..0,
+        kind: "",
+        no_map: false,
+    };
+}
+
+/// 最多记录的保留区数量。
+const MAX_RESERVED: usize = 32;
+
+/// 最多使用的内存区数量。
+pub(crate) const MAX_MEMORY: usize = 16;
+
+static mut RESERVED: [Reserved; MAX_RESERVED] = [Reserved::EMPTY; MAX_RESERVED];
+static mut RESERVED_LEN: usize = 0;
+
+/// 初始化页分配器时排除的所有保留区。
+pub(crate) fn reserved() -> &'static [Reserved] {
+    unsafe { &RESERVED[..RESERVED_LEN] }
+}
+
+/// 禁止映射的区域向外扩展到整页，内核线性区在它周围改用 4 KiB 页，不会波及相邻的内存。
+fn no_map_range(range: Range<usize>) -> Range<usize> {
+    (range.start & !ALIGN)..((range.end + ALIGN) & !ALIGN)
+}
+
+/// 记录一个保留区。
+fn reserve(range: Range<usize>, kind: &'static str, no_map: bool) {
+    let range = if no_map { no_map_range(range) } else { range };
+    if range.is_empty() {
+        return;
+    }
+    log::info!(
+        "reserved {:#x}..{:#x} ({kind}{})",
+        range.start,
+        range.end,
+        if no_map { ", no-map" } else { "" },
+    );
+    unsafe {
+        if RESERVED_LEN < MAX_RESERVED {
+            RESERVED[RESERVED_LEN] = Reserved {
+                range,
+                kind,
+                no_map,
+            };
+            RESERVED_LEN += 1;
+        } else {
+            panic!("too many reserved regions");
+        }
+    }
+}
+
 /// 建立页分配器。
 ///
+/// 设备树 `/reserved-memory` 的子节点、头部的 memreserve 块、设备树本身、initrd 和启动程序自身都不交给页分配器。
+/// 页分配器建立后，第一次分配用于把设备树复制到内核内存中，原来的位置随后交给页分配器。
+/// 最后为交给页分配器的物理内存建立页帧元数据表。
+///
 /// 返回线性地址空间的结束位置。
-pub(crate) fn init_global(layout: &KernelLayout, dtb_addr: usize) -> usize {
-    use dtb_walker::{Dtb, DtbObj, HeaderError::*, Property, WalkOperation::*};
+pub(crate) fn init_global(layout: &KernelLayout, dt: DeviceTree) -> usize {
+    use dtb_walker::{DtbObj, Property, WalkOperation::*};
     unsafe { GLOBAL.init(Sv39::PAGE_BITS, non_null::<u8>(layout.start())) };
+    // 设备树本身和 memreserve 块
+    reserve(dt.p_range(), "dtb", false);
+    for range in dt.mem_reserve() {
+        reserve(range, "memreserve", false);
+    }
+    // initrd
+    if let Some(initrd) = payload::initrd(&dt) {
+        reserve(initrd, "initrd", false);
+    }
+    // /reserved-memory 的子节点
+    let mut node_start = 0;
+    let mut no_map = false;
+    dt.dtb().walk(|path, obj| match obj {
+        DtbObj::SubNode { name } => {
+            if path.is_root() && name.starts_with("reserved-memory") {
+                StepInto
+            } else if path.name().starts_with("reserved-memory") {
+                node_start = unsafe { RESERVED_LEN };
+                no_map = false;
+                StepInto
+            } else {
+                StepOver
+            }
+        }
+        DtbObj::Property(Property::Reg(reg))
+            if !path.is_root() && !path.name().starts_with("reserved-memory") =>
+        {
+            for segment in reg {
+                reserve(segment, "reserved-memory", no_map);
+            }
+            StepOver
+        }
+        DtbObj::Property(Property::General { name, .. })
+            if name.starts_with("no-map")
+                && !path.is_root()
+                && !path.name().starts_with("reserved-memory") =>
+        {
+            // no-map 可能出现在 reg 之后，补上已经记录的区域
+            no_map = true;
+            let regions = unsafe { &mut RESERVED[node_start..RESERVED_LEN] };
+            for region in regions.iter_mut().filter(|r| !r.no_map) {
+                region.range = no_map_range(region.range.clone());
+                region.no_map = true;
+                log::info!(
+                    "reserved {:#x}..{:#x} ({}, no-map)",
+                    region.range.start,
+                    region.range.end,
+                    region.kind,
+                );
+            }
+            StepOver
+        }
+        DtbObj::Property(_) => StepOver,
+    });
     // 从设备树解析内存信息
-    let dtb = unsafe {
-        Dtb::from_raw_parts_filtered(layout.p_to_v(dtb_addr) as _, |e| {
-            matches!(e, Misaligned(4) | LastCompVersion(_))
-        })
-    };
     let mut max = 0;
-    dtb.unwrap().walk(|path, obj| match obj {
+    let mut memory = [(); MAX_MEMORY].map(|_| 0..0);
+    let mut memory_len = 0;
+    let mut budget = MEM.get();
+    dt.dtb().walk(|path, obj| match obj {
         DtbObj::SubNode { name } => {
             if path.is_root() && name.starts_with("memory") {
                 StepInto
@@ -29,21 +165,114 @@ pub(crate) fn init_global(layout: &KernelLayout, dtb_addr: usize) -> usize {
         DtbObj::Property(Property::Reg(reg)) if path.name().starts_with("memory") => {
             let p_start = layout.v_to_p(layout.start());
             for segment in reg {
-                unsafe {
-                    let (ptr, size) = if segment.contains(&p_start) {
-                        // 从启动页表之后由页帧分配器管理
-                        let addr = layout.boot_pt_root() + 4096;
-                        (addr, layout.p_to_v(segment.end) - addr)
-                    } else {
-                        (layout.p_to_v(segment.start), segment.len())
-                    };
-                    max = max.max(ptr + size);
-                    GLOBAL.transfer(non_null::<u8>(ptr), size);
-                };
+                if segment.contains(&p_start) {
+                    // 从启动页表之后由页帧分配器管理
+                    reserve(
+                        segment.start..layout.v_to_p(layout.boot_pt_end()),
+                        "boot stage",
+                        false,
+                    );
+                }
+                // 线性区之外的内存无法访问
+                let limit = layout.offset().wrapping_neg();
+                if segment.end > limit {
+                    log::warn!(
+                        "memory {:#x}..{:#x} beyond linear area is ignored",
+                        segment.start.max(limit),
+                        segment.end,
+                    );
+                }
+                let segment = segment.start.min(limit)..segment.end.min(limit);
+                // 按 mem 参数截断
+                let segment = segment.start..segment.start + segment.len().min(budget);
+                budget -= segment.len();
+                if segment.is_empty() {
+                    continue;
+                }
+                if memory_len == MAX_MEMORY {
+                    log::warn!(
+                        "too many memory regions, {:#x}..{:#x} ignored",
+                        segment.start,
+                        segment.end,
+                    );
+                    continue;
+                }
+                memory[memory_len] = segment.clone();
+                memory_len += 1;
+                max = max.max(layout.p_to_v(segment.end));
+                transfer(layout, segment);
             }
             StepOut
         }
         DtbObj::Property(_) => StepOver,
     });
+    // 重定位设备树
+    let relocated = unsafe {
+        let size = (dt.p_range().len() + ALIGN) & !ALIGN;
+        let (ptr, _) = GLOBAL
+            .allocate_layout::<u8>(Layout::from_size_align_unchecked(size, ALIGN + 1))
+            .unwrap();
+        let bytes = dt.as_bytes();
+        core::ptr::copy_nonoverlapping(bytes.as_ptr(), ptr.as_ptr(), bytes.len());
+        DeviceTree::from_raw(ptr.as_ptr()).unwrap()
+    };
+    let original = dt.p_range();
+    log::info!(
+        "device tree relocated {:#x}..{:#x} -> {:#x}..{:#x}",
+        original.start,
+        original.end,
+        relocated.p_range().start,
+        relocated.p_range().end,
+    );
+    unsafe { &mut RESERVED[..RESERVED_LEN] }
+        .iter_mut()
+        .find(|r| r.kind == "dtb")
+        .unwrap()
+        .range = relocated.p_range();
+    // 原来的设备树还给页帧分配器，只取可用内存中的部分。
+    // 两端不满一页的部分没有交给过页帧分配器，向外扩展到整页，再由 transfer 排除其中的保留区
+    let pages = (original.start & !ALIGN)..((original.end + ALIGN) & !ALIGN);
+    for segment in &memory[..memory_len] {
+        let start = pages.start.max(segment.start);
+        let end = pages.end.min(segment.end);
+        if start < end {
+            transfer(layout, start..end);
+        }
+    }
+    device_tree::init(relocated);
+    frame::init(&memory[..memory_len]);
     max
 }
+
+bench!(frame_alloc, |b| {
+    b.iter(|| unsafe {
+        let layout = Layout::from_size_align_unchecked(ALIGN + 1, ALIGN + 1);
+        let (ptr, _) = GLOBAL.allocate_layout::<u8>(layout).unwrap();
+        GLOBAL.deallocate_layout(ptr, layout);
+    })
+});
+
+/// 把 `range` 中不与保留区重叠的部分交给页帧分配器。
+fn transfer(layout: &KernelLayout, range: Range<usize>) {
+    match reserved()
+        .iter()
+        .find(|r| r.range.start < range.end && range.start < r.range.end)
+    {
+        Some(r) => {
+            let r = r.range.clone();
+            if range.start < r.start {
+                transfer(layout, range.start..r.start);
+            }
+            if r.end < range.end {
+                transfer(layout, r.end..range.end);
+            }
+        }
+        None => {
+            let start = (range.start + ALIGN) & !ALIGN;
+            let end = range.end & !ALIGN;
+            if start < end {
+                unsafe { GLOBAL.transfer(non_null::<u8>(layout.p_to_v(start)), end - start) };
+            }
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! 内核参数。
+//!
+//! 参数来自设备树 `/chosen/bootargs`，形如 `log=debug smp=2 mem=512M`。
+//! 模块用 [`param!`] 声明参数和默认值，参数被登记在 `.param` 段中，解析时由 [`params`] 发现。
+
+use crate::device_tree::DeviceTree;
+use core::fmt;
+use spin::Once;
+
+/// 声明一个内核参数并登记到 `.param` 段。
+///
+/// ```ignore
+/// param! {
+///     /// 最多启动的硬件线程数量。
+///     pub(crate) static SMP: Param<usize> = Param::new("smp", "最多启动的硬件线程数量", 8);
+/// }
+/// ```
+macro_rules! param {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;) => {
+        $(#[$attr])*
+        $vis static $name: $ty = $init;
+        const _: () = {
+            #[used]
+            #[link_section = ".param"]
+            static ENTRY: &dyn $crate::param::Setter = &$name;
+        };
+    };
+}
+
+/// 命令行的最大长度。
+const MAX_LEN: usize = 1024;
+
+/// 命令行的副本。
+///
+/// 设备树原来的位置会交给页分配器，参数值引用这个副本。
+static COMMAND_LINE: Once<([u8; MAX_LEN], usize)> = Once::new();
+
+/// 可以从命令行解析的参数值。
+pub(crate) trait Parse: Sized {
+    /// 解析参数值。没有 `=` 的参数以空串解析。
+    fn parse(value: &'static str) -> Option<Self>;
+}
+
+impl Parse for &'static str {
+    #[inline]
+    fn parse(value: &'static str) -> Option<Self> {
+        Some(value)
+    }
+}
+
+impl Parse for bool {
+    fn parse(value: &'static str) -> Option<Self> {
+        match value {
+            "" | "1" | "on" | "true" | "yes" => Some(true),
+            "0" | "off" | "false" | "no" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// 支持 `0x` 前缀和 `K`/`M`/`G` 后缀。
+impl Parse for usize {
+    fn parse(value: &'static str) -> Option<Self> {
+        let (value, shift) = match value.as_bytes().last()? {
+            b'K' | b'k' => (&value[..value.len() - 1], 10),
+            b'M' | b'm' => (&value[..value.len() - 1], 20),
+            b'G' | b'g' => (&value[..value.len() - 1], 30),
+            _ => (value, 0),
+        };
+        let n = match value.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => value.parse(),
+        };
+        n.ok()?.checked_mul(1 << shift)
+    }
+}
+
+/// 一个内核参数。
+pub(crate) struct Param<T: 'static> {
+    name: &'static str,
+    help: &'static str,
+    default: T,
+    value: Once<T>,
+}
+
+impl<T: Parse + Copy> Param<T> {
+    /// 声明一个参数。
+    pub const fn new(name: &'static str, help: &'static str, default: T) -> Self {
+        Self {
+            name,
+            help,
+            default,
+            value: Once::new(),
+        }
+    }
+
+    /// 参数值，命令行没有设置时是默认值。
+    #[inline]
+    pub fn get(&self) -> T {
+        *self.value.get().unwrap_or(&self.default)
+    }
+}
+
+/// 日志级别，解析时检查是否是 `log` 认识的级别。
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LogLevel(pub &'static str);
+
+impl Parse for LogLevel {
+    fn parse(value: &'static str) -> Option<Self> {
+        value.parse::<log::LevelFilter>().ok().map(|_| Self(value))
+    }
+}
+
+/// 类型擦除的参数，用于登记。
+pub(crate) trait Setter: Sync {
+    fn name(&self) -> &'static str;
+    fn is_set(&self) -> bool;
+    fn set(&self, value: &'static str) -> bool;
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result;
+}
+
+impl<T: Parse + Copy + fmt::Debug + Send + Sync> Setter for Param<T> {
+    #[inline]
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[inline]
+    fn is_set(&self) -> bool {
+        self.value.is_completed()
+    }
+
+    fn set(&self, value: &'static str) -> bool {
+        match T::parse(value) {
+            Some(value) => {
+                self.value.call_once(|| value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={:?} ({})", self.name, self.get(), self.help)
+    }
+}
+
+/// 从设备树读取 `/chosen/bootargs` 并解析参数。
+///
+/// 只能调用一次，未知的参数、无法解析的值和重复的参数打印警告，重复的参数以第一次出现为准。
+pub(crate) fn parse(dt: &DeviceTree) {
+    use dtb_walker::{DtbObj, Property, WalkOperation::*};
+    let (buf, len) = COMMAND_LINE.call_once(|| {
+        let mut buf = [0u8; MAX_LEN];
+        let mut len = 0;
+        dt.dtb().walk(|path, obj| match obj {
+            DtbObj::SubNode { name } => {
+                if path.is_root() && name.starts_with("chosen") {
+                    StepInto
+                } else {
+                    StepOver
+                }
+            }
+            DtbObj::Property(Property::General { name, value })
+                if path.name().starts_with("chosen") && name.starts_with("bootargs") =>
+            {
+                let value = value.split(|b| *b == 0).next().unwrap_or(&[]);
+                len = value.len().min(MAX_LEN);
+                buf[..len].copy_from_slice(&value[..len]);
+                StepOut
+            }
+            DtbObj::Property(_) => StepOver,
+        });
+        (buf, len)
+    });
+    let cmdline = match core::str::from_utf8(&buf[..*len]) {
+        Ok(s) => s,
+        Err(_) => {
+            log::warn!("bootargs is not valid utf-8, ignored");
+            return;
+        }
+    };
+    log::info!("command line: {cmdline:?}");
+    for arg in cmdline.split_whitespace() {
+        let (key, value) = arg.split_once('=').unwrap_or((arg, ""));
+        match params().iter().find(|p| p.name() == key) {
+            Some(param) if param.is_set() => {
+                log::warn!("duplicate kernel parameter `{key}`, {value:?} ignored")
+            }
+            Some(param) if param.set(value) => {}
+            Some(_) => log::warn!("invalid value for kernel parameter `{key}`: {value:?}"),
+            None => log::warn!("unknown kernel parameter `{key}`"),
+        }
+    }
+    for param in params() {
+        log::debug!("{}", Display(*param));
+    }
+}
+
+/// 所有由 [`param!`] 登记的参数。
+fn params() -> &'static [&'static dyn Setter] {
+    let (start, end): (usize, usize);
+    unsafe {
+        core::arch::asm!(
+            "lla {0}, _param_start",
+            "lla {1}, _param_end",
+            out(reg) start,
+            out(reg) end,
+        );
+        core::slice::from_raw_parts(
+            start as *const &dyn Setter,
+            (end - start) / core::mem::size_of::<&dyn Setter>(),
+        )
+    }
+}
+
+struct Display<'a>(&'a dyn Setter);
+
+impl fmt::Display for Display<'_> {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
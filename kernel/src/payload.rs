@@ -0,0 +1,333 @@
+﻿//! 下一阶段载荷。
+//!
+//! 载荷可以在构建时通过 `PAYLOAD` 环境变量嵌入，也可以由引导程序放在设备树 `/chosen` 的
+//! `linux,initrd-start` 和 `linux,initrd-end` 描述的位置。
+//!
+//! 载荷可以是 ELF64 可执行文件，也可以是原始二进制。后者被加载到 [`RAW_BASE`]，只读可执行，
+//! 紧接着映射 [`RAW_SCRATCH_PAGES`] 页可读写不可执行的清零内存，可以用作栈和可写数据。
+//! 载荷被复制到新分配的页中，映射到一个全新的地址空间，然后以
+//! `a0 = hartid`、`a1 = 重定位后的设备树物理地址`、`a2 = BootInfo 在线性区中的虚地址` 跳转到入口，载荷需要自己设置栈。
+//! 跳转前其他硬件线程已经通过 SBI 停止，载荷可以用 `hart_start` 重新启动它们。
+//! 载荷的地址空间没有恒等映射，物理地址要加上 [`BootInfo::linear_offset`] 才能访问。
+
+use crate::{
+    boot::PagingMode,
+    device_tree::{self, be_usize, DeviceTree},
+    elf::{self, Elf},
+    hart,
+    layout::KernelLayout,
+    non_null, page,
+    space::{AddressSpace, PageManager, PagePolicy},
+    Global, LAYOUT,
+};
+use core::{ops::Range, ptr::NonNull};
+use page_table::{Pte, VAddr, VmFlags, VmMeta, PPN, VPN};
+use riscv::register::{satp, sie, sstatus};
+
+/// 构建时嵌入的载荷，没有嵌入时为空。
+static EMBEDDED: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/payload.bin"));
+
+/// 原始二进制载荷的加载虚地址，也是入口。
+const RAW_BASE: usize = 0x8000_0000;
+
+/// 原始二进制载荷之后可读写的页数。
+const RAW_SCRATCH_PAGES: usize = 16;
+
+/// 找到下一阶段载荷。
+///
+/// 优先使用嵌入的载荷，其次使用设备树中的 initrd。
+pub(crate) fn locate(layout: &KernelLayout) -> Option<&'static [u8]> {
+    if !EMBEDDED.is_empty() {
+        return Some(EMBEDDED);
+    }
+    initrd(device_tree::get()).map(|range| unsafe {
+        core::slice::from_raw_parts(layout.p_to_v(range.start) as *const u8, range.len())
+    })
+}
+
+/// 从设备树 `/chosen` 读取 initrd 的物理地址范围。
+pub(crate) fn initrd(dt: &DeviceTree) -> Option<Range<usize>> {
+    use dtb_walker::{DtbObj, Property, WalkOperation::*};
+    let mut start = None;
+    let mut end = None;
+    dt.dtb().walk(|path, obj| match obj {
+        DtbObj::SubNode { name } => {
+            if path.is_root() && name.starts_with("chosen") {
+                StepInto
+            } else {
+                StepOver
+            }
+        }
+        DtbObj::Property(Property::General { name, value })
+            if path.name().starts_with("chosen") =>
+        {
+            if name.starts_with("linux,initrd-start") {
+                start = Some(be_usize(value));
+            } else if name.starts_with("linux,initrd-end") {
+                end = Some(be_usize(value));
+            }
+            StepOver
+        }
+        DtbObj::Property(_) => StepOver,
+    });
+    match (start, end) {
+        (Some(start), Some(end)) if start < end => Some(start..end),
+        _ => None,
+    }
+}
+
+/// 载荷的启动信息。
+///
+/// 物理内存布局由 `regions` 描述，保留区与可用区重叠时以保留区为准。
+///
+/// 这个结构由载荷读取。
+#[allow(unused)]
+#[repr(C)]
+pub(crate) struct BootInfo {
+    /// 固定为 [`BootInfo::MAGIC`]。
+    pub magic: [u8; 8],
+    /// 结构版本，不兼容的修改会增加版本号。
+    pub version: u32,
+    /// 结构的字节数。
+    pub size: u32,
+    /// 启动载荷的硬件线程。
+    pub hartid: usize,
+    /// 设备树物理地址。
+    pub dtb: usize,
+    /// 线性区虚地址相对物理地址的偏移，载荷的地址空间中保留了线性区。
+    pub linear_offset: usize,
+    /// 载荷入口虚地址。
+    pub entry: usize,
+    /// `regions` 中有效项的数量。
+    pub regions_len: usize,
+    /// 物理内存区域。
+    pub regions: [MemRegion; BootInfo::MAX_REGIONS],
+}
+
+/// 物理内存区域。
+#[allow(unused)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct MemRegion {
+    /// 起始物理地址。
+    pub start: usize,
+    /// 结束物理地址。
+    pub end: usize,
+    /// 区域类型。
+    pub kind: RegionKind,
+}
+
+/// 物理内存区域类型。
+#[repr(usize)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum RegionKind {
+    /// 设备树描述的内存。
+    Usable = 0,
+    /// 启动程序自身：内核镜像和启动栈，跳转过程中仍在使用。
+    BootStage = 1,
+    /// 交给载荷的内存：载荷镜像、载荷页表和这个结构。
+    Handoff = 2,
+    /// 启动程序排除的区域：设备树、固件等。
+    Reserved = 3,
+}
+
+impl BootInfo {
+    /// 魔数。
+    pub const MAGIC: [u8; 8] = *b"PERFBOOT";
+    /// 当前版本。
+    pub const VERSION: u32 = 1;
+    /// 最多描述的内存区域数量。
+    pub const MAX_REGIONS: usize = 32;
+
+    fn push(&mut self, start: usize, end: usize, kind: RegionKind) {
+        if self.regions_len < Self::MAX_REGIONS {
+            self.regions[self.regions_len] = MemRegion { start, end, kind };
+            self.regions_len += 1;
+        } else {
+            log::warn!("boot info full, region {start:#x}..{end:#x} ({kind:?}) dropped");
+        }
+    }
+}
+
+/// 把 `image` 作为下一阶段载荷加载到新的地址空间并跳转过去。
+pub(crate) fn launch<Meta: PagingMode>(hartid: usize, layout: &KernelLayout, image: &[u8]) -> ! {
+    let page_bits = Meta::PAGE_BITS;
+    let dt = device_tree::get();
+    let dtb_addr = dt.p_range().start;
+    let elf = if elf::is_elf(image) {
+        match Elf::parse(image) {
+            Ok(elf) => Some(elf),
+            Err(e) => panic!("invalid payload: {e:?}"),
+        }
+    } else {
+        None
+    };
+    let image_pages = match &elf {
+        Some(elf) => elf.pages::<Meta>(),
+        None => ((image.len() + (1 << page_bits) - 1) >> page_bits) + RAW_SCRATCH_PAGES,
+    };
+    // 载荷需要的所有页都从一块连续内存分配，以便在启动信息里描述
+    let kernel_tables = AddressSpace::<Meta, Arena>::kernel_tables();
+    let arena_pages = image_pages + image_pages / 256 + 4 * Meta::MAX_LEVEL + 2 + kernel_tables;
+    let mut arena = Arena::new::<Meta>(arena_pages);
+    let reserved = arena.range();
+    let info_ppn = PageManager::<Meta>::allocate(&mut arena, VmFlags::VALID, 1).ppn();
+    let mut space = AddressSpace::<Meta, Arena>::new(arena);
+    // 保留线性区，跳转代码和启动信息都通过线性区访问
+    space.kernel(VmFlags::build_from_str("DAG_XWRV"));
+    // 加载载荷
+    let entry = match elf {
+        Some(elf) => match elf.load(&mut space, false) {
+            Ok(entry) => entry,
+            Err(e) => panic!("failed to load payload: {e:?}"),
+        },
+        None => {
+            let frames = space.manager().allocate(VmFlags::VALID, image_pages).ppn();
+            let dst = PageManager::<Meta>::p_to_v::<u8>(&Global, frames);
+            unsafe {
+                core::ptr::copy_nonoverlapping(image.as_ptr(), dst.as_ptr(), image.len());
+                core::ptr::write_bytes(
+                    dst.as_ptr().add(image.len()),
+                    0,
+                    (image_pages << page_bits) - image.len(),
+                );
+            }
+            // 代码只读可执行，之后的页可写不可执行
+            let base = VAddr::<Meta>::new(RAW_BASE).floor();
+            let code = VAddr::<Meta>::new(RAW_BASE + image.len()).ceil();
+            let end = VPN::new(base.val() + image_pages);
+            let scratch = PPN::new(frames.val() + (code.val() - base.val()));
+            let rx = VmFlags::build_from_str("DA__X_RV");
+            let rw = VmFlags::build_from_str("DA___WRV");
+            space.map_to(base..code, frames, rx, PagePolicy::Huge);
+            space.map_to(code..end, scratch, rw, PagePolicy::Huge);
+            RAW_BASE
+        }
+    };
+    // 填写启动信息
+    let info_ptr = PageManager::<Meta>::p_to_v::<BootInfo>(&Global, info_ppn);
+    let info = unsafe { &mut *info_ptr.as_ptr() };
+    info.magic = BootInfo::MAGIC;
+    info.version = BootInfo::VERSION;
+    info.size = core::mem::size_of::<BootInfo>() as _;
+    info.hartid = hartid;
+    info.dtb = dtb_addr;
+    info.linear_offset = layout.offset();
+    info.entry = entry;
+    info.regions_len = 0;
+    for memory in memory_regions(dt) {
+        info.push(memory.start, memory.end, RegionKind::Usable);
+    }
+    info.push(
+        layout.v_to_p(layout.start()),
+        layout.v_to_p(layout.boot_pt_root()),
+        RegionKind::BootStage,
+    );
+    info.push(reserved.start, reserved.end, RegionKind::Handoff);
+    for region in page::reserved() {
+        info.push(region.range.start, region.range.end, RegionKind::Reserved);
+    }
+    log::info!(
+        "launch payload: {} bytes at {entry:#x}, boot info at {:#x}",
+        image.len(),
+        info_ptr.as_ptr() as usize,
+    );
+    // 其他硬件线程还在使用内核页表，停止它们再交出内存
+    hart::stop_others();
+    // 关闭中断，切换地址空间并跳转
+    unsafe {
+        sstatus::clear_sie();
+        sie::clear_stimer();
+        satp::set(Meta::MODE, 0, space.root_ppn().val());
+        riscv::asm::sfence_vma_all();
+        core::arch::asm!(
+            "jr {entry}",
+            entry = in(reg) entry,
+            in("a0") hartid,
+            in("a1") dtb_addr,
+            in("a2") info_ptr.as_ptr() as usize,
+            options(noreturn),
+        )
+    }
+}
+
+/// 从设备树读取物理内存区域。
+fn memory_regions(dt: &DeviceTree) -> impl Iterator<Item = Range<usize>> {
+    use dtb_walker::{DtbObj, Property, WalkOperation::*};
+    let mut regions = [(); BootInfo::MAX_REGIONS].map(|_| 0..0);
+    let mut len = 0;
+    dt.dtb().walk(|path, obj| match obj {
+        DtbObj::SubNode { name } => {
+            if path.is_root() && name.starts_with("memory") {
+                StepInto
+            } else {
+                StepOver
+            }
+        }
+        DtbObj::Property(Property::Reg(reg)) if path.name().starts_with("memory") => {
+            for segment in reg {
+                if len < regions.len() {
+                    regions[len] = segment;
+                    len += 1;
+                }
+            }
+            StepOut
+        }
+        DtbObj::Property(_) => StepOver,
+    });
+    regions.into_iter().take(len)
+}
+
+/// 从一块连续内存顺序分配页的页管理器。
+///
+/// 载荷启动后这块内存归载荷所有，所以不支持回收。
+pub(crate) struct Arena {
+    next: usize,
+    end: usize,
+}
+
+impl Arena {
+    /// 从全局页帧分配器取得 `pages` 个连续页。
+    fn new<Meta: VmMeta>(pages: usize) -> Self {
+        let ppn = PageManager::<Meta>::allocate(&mut Global, VmFlags::VALID, pages).ppn();
+        let next = ppn.val() << Meta::PAGE_BITS;
+        Self {
+            next,
+            end: next + (pages << Meta::PAGE_BITS),
+        }
+    }
+
+    /// 这块内存的物理地址范围。
+    fn range(&self) -> Range<usize> {
+        self.next..self.end
+    }
+}
+
+impl<Meta: VmMeta> PageManager<Meta> for Arena {
+    fn allocate(&mut self, flags: VmFlags<Meta>, len: usize) -> Pte<Meta> {
+        let addr = self.next;
+        self.next += len << Meta::PAGE_BITS;
+        assert!(self.next <= self.end, "payload arena exhausted");
+        flags.build_pte(PPN::new(addr >> Meta::PAGE_BITS))
+    }
+
+    fn deallocate(&mut self, _pte: Pte<Meta>, _len: usize) {
+        unreachable!("payload arena never deallocates")
+    }
+
+    fn share(&mut self, _pte: Pte<Meta>, _len: usize) -> (Pte<Meta>, Pte<Meta>) {
+        unreachable!("payload arena never shares")
+    }
+
+    fn exclude(&mut self, _pte: Pte<Meta>, _len: usize) -> Pte<Meta> {
+        unreachable!("payload arena never shares")
+    }
+
+    fn p_to_v<T>(&self, ppn: PPN<Meta>) -> NonNull<T> {
+        non_null(unsafe { LAYOUT.p_to_v(ppn.val() << Meta::PAGE_BITS) })
+    }
+
+    fn v_to_p<T>(&self, ptr: NonNull<T>) -> PPN<Meta> {
+        PPN::new(unsafe { LAYOUT.v_to_p(ptr.as_ptr() as _) } >> Meta::PAGE_BITS)
+    }
+}
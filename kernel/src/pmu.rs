@@ -0,0 +1,417 @@
+﻿//! 性能计数器。
+//!
+//! 通过 SBI PMU 扩展配置硬件和固件事件。固件不支持 PMU 扩展时，
+//! 周期数和指令数退回到直接读 `cycle` 和 `instret`，其他事件不可用。
+//!
+//! 计数器编号和事件编号的含义见 SBI 规范的 PMU 一章。
+
+use crate::timer;
+use core::{fmt, time::Duration};
+use sbi_rt::SbiRet;
+use spin::Once;
+
+/// PMU 扩展编号，`"PMU"`。
+const EID_PMU: usize = 0x504d55;
+
+/// 配置时清零计数器。
+const CFG_FLAG_CLEAR_VALUE: usize = 1 << 1;
+/// 不计 M 态。
+const CFG_FLAG_SET_MINH: usize = 1 << 7;
+/// 停止时释放计数器。
+const STOP_FLAG_RESET: usize = 1 << 0;
+
+/// 最多支持的计数器数量。
+const MAX_COUNTERS: usize = 64;
+
+/// 一个 [`CounterGroup`] 最多测量的事件数量，不含周期数和指令数。
+pub(crate) const MAX_EVENTS: usize = 8;
+
+/// 成功时取出返回值。
+#[inline]
+fn ok(ret: SbiRet) -> Option<usize> {
+    (ret.error == 0).then_some(ret.value)
+}
+
+/// 一个事件。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct Event {
+    /// SBI 事件编号，`[19:16]` 是类型，`[15:0]` 是代码。
+    idx: usize,
+    /// 输出中使用的名字。
+    name: &'static str,
+}
+
+impl Event {
+    const fn hardware(code: usize, name: &'static str) -> Self {
+        Self { idx: code, name }
+    }
+
+    const fn firmware(code: usize, name: &'static str) -> Self {
+        Self {
+            idx: (15 << 16) | code,
+            name,
+        }
+    }
+
+    pub const CYCLES: Self = Self::hardware(1, "cycles");
+    pub const INSTRUCTIONS: Self = Self::hardware(2, "instret");
+    pub const CACHE_REFERENCES: Self = Self::hardware(3, "cache-references");
+    pub const CACHE_MISSES: Self = Self::hardware(4, "cache-misses");
+    pub const BRANCH_INSTRUCTIONS: Self = Self::hardware(5, "branches");
+    pub const BRANCH_MISSES: Self = Self::hardware(6, "branch-misses");
+    pub const BUS_CYCLES: Self = Self::hardware(7, "bus-cycles");
+    pub const STALLED_CYCLES_FRONTEND: Self = Self::hardware(8, "stalled-cycles-frontend");
+    pub const STALLED_CYCLES_BACKEND: Self = Self::hardware(9, "stalled-cycles-backend");
+    pub const REF_CPU_CYCLES: Self = Self::hardware(10, "ref-cycles");
+    pub const FW_MISALIGNED_LOAD: Self = Self::firmware(0, "fw-misaligned-load");
+    pub const FW_MISALIGNED_STORE: Self = Self::firmware(1, "fw-misaligned-store");
+    pub const FW_ACCESS_LOAD: Self = Self::firmware(2, "fw-access-load");
+    pub const FW_ACCESS_STORE: Self = Self::firmware(3, "fw-access-store");
+    pub const FW_ILLEGAL_INSN: Self = Self::firmware(4, "fw-illegal-insn");
+    pub const FW_SET_TIMER: Self = Self::firmware(5, "fw-set-timer");
+    pub const FW_IPI_SENT: Self = Self::firmware(6, "fw-ipi-sent");
+    pub const FW_IPI_RECEIVED: Self = Self::firmware(7, "fw-ipi-received");
+    pub const FW_FENCE_I_SENT: Self = Self::firmware(8, "fw-fence-i-sent");
+    pub const FW_FENCE_I_RECEIVED: Self = Self::firmware(9, "fw-fence-i-received");
+    pub const FW_SFENCE_VMA_SENT: Self = Self::firmware(10, "fw-sfence-vma-sent");
+    pub const FW_SFENCE_VMA_RECEIVED: Self = Self::firmware(11, "fw-sfence-vma-received");
+
+    /// 所有已知事件，[`CYCLES`](Self::CYCLES) 和 [`INSTRUCTIONS`](Self::INSTRUCTIONS) 总是被测量，不在其中。
+    pub const ALL: &'static [Self] = &[
+        Self::CACHE_REFERENCES,
+        Self::CACHE_MISSES,
+        Self::BRANCH_INSTRUCTIONS,
+        Self::BRANCH_MISSES,
+        Self::BUS_CYCLES,
+        Self::STALLED_CYCLES_FRONTEND,
+        Self::STALLED_CYCLES_BACKEND,
+        Self::REF_CPU_CYCLES,
+        Self::FW_MISALIGNED_LOAD,
+        Self::FW_MISALIGNED_STORE,
+        Self::FW_ACCESS_LOAD,
+        Self::FW_ACCESS_STORE,
+        Self::FW_ILLEGAL_INSN,
+        Self::FW_SET_TIMER,
+        Self::FW_IPI_SENT,
+        Self::FW_IPI_RECEIVED,
+        Self::FW_FENCE_I_SENT,
+        Self::FW_FENCE_I_RECEIVED,
+        Self::FW_SFENCE_VMA_SENT,
+        Self::FW_SFENCE_VMA_RECEIVED,
+    ];
+
+    /// 事件名。
+    #[inline]
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// 按名字查找事件。
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::CYCLES, Self::INSTRUCTIONS]
+            .iter()
+            .chain(Self::ALL)
+            .find(|e| e.name == name)
+            .copied()
+    }
+}
+
+/// 计数器信息，`sbi_pmu_counter_get_info` 的返回值。
+#[derive(Clone, Copy)]
+struct CounterInfo(usize);
+
+impl CounterInfo {
+    /// 是否是固件计数器。
+    #[inline]
+    fn is_firmware(self) -> bool {
+        self.0 >> (usize::BITS - 1) != 0
+    }
+
+    /// 硬件计数器的 CSR 编号。
+    #[inline]
+    fn csr(self) -> usize {
+        self.0 & 0xfff
+    }
+
+    /// 硬件计数器的位宽。
+    #[inline]
+    fn width(self) -> u32 {
+        ((self.0 >> 12) & 0x3f) as u32 + 1
+    }
+}
+
+/// 固件提供的计数器。
+struct Pmu {
+    num: usize,
+    info: [CounterInfo; MAX_COUNTERS],
+}
+
+/// 固件不支持 PMU 扩展时为 `None`。
+static PMU: Once<Option<Pmu>> = Once::new();
+
+/// 探测 PMU 扩展并枚举计数器。
+pub(crate) fn init() {
+    let pmu = PMU.call_once(|| {
+        if sbi_rt::probe_extension(EID_PMU) == 0 {
+            log::warn!("SBI PMU extension not available, fall back to rdcycle/rdinstret");
+            return None;
+        }
+        let num = sbi_rt::pmu_num_counters().min(MAX_COUNTERS);
+        let mut info = [CounterInfo(0); MAX_COUNTERS];
+        for (i, info) in info.iter_mut().enumerate().take(num) {
+            *info = CounterInfo(ok(sbi_rt::pmu_counter_get_info(i)).unwrap_or(0));
+        }
+        Some(Pmu { num, info })
+    });
+    if let Some(pmu) = pmu {
+        let firmware = pmu.info[..pmu.num]
+            .iter()
+            .filter(|i| i.is_firmware())
+            .count();
+        log::info!(
+            "SBI PMU: {} hardware counter(s), {firmware} firmware counter(s)",
+            pmu.num - firmware,
+        );
+        for (i, info) in pmu.info[..pmu.num].iter().enumerate() {
+            if !info.is_firmware() {
+                log::debug!("counter {i}: csr {:#x}, {} bits", info.csr(), info.width());
+            }
+        }
+        for event in Event::ALL {
+            match Counter::configure(pmu, *event) {
+                Some(counter) => counter.release(),
+                None => log::debug!("event {} not supported", event.name()),
+            }
+        }
+    }
+    let (_, sample) = CounterGroup::measure(&[Event::CACHE_MISSES, Event::BRANCH_MISSES], || {
+        timer::delay(Duration::from_micros(10))
+    });
+    log::debug!("pmu self test: delay(10us) {sample}");
+}
+
+/// 一个正在计数的计数器。
+struct Counter {
+    /// SBI 计数器编号，直接读 CSR 时为 `None`。
+    idx: Option<usize>,
+    /// 硬件计数器的 CSR 编号，固件计数器为 `None`。
+    csr: Option<usize>,
+    /// 计数器位宽的掩码，用于处理回绕。
+    mask: u64,
+}
+
+impl Counter {
+    /// 不经过 SBI 读 `cycle`。
+    const RDCYCLE: Self = Self {
+        idx: None,
+        csr: Some(0xc00),
+        mask: u64::MAX,
+    };
+
+    /// 不经过 SBI 读 `instret`。
+    const RDINSTRET: Self = Self {
+        idx: None,
+        csr: Some(0xc02),
+        mask: u64::MAX,
+    };
+
+    /// 找一个能计数 `event` 的计数器并开始计数。
+    fn configure(pmu: &Pmu, event: Event) -> Option<Self> {
+        let mask = if pmu.num >= usize::BITS as usize {
+            usize::MAX
+        } else {
+            (1 << pmu.num) - 1
+        };
+        let flags = CFG_FLAG_CLEAR_VALUE | CFG_FLAG_SET_MINH;
+        let ret = sbi_rt::pmu_counter_config_matching(0, mask, flags, event.idx, 0);
+        let idx = ok(ret)?;
+        let info = *pmu.info.get(idx)?;
+        let counter = Self {
+            idx: Some(idx),
+            csr: (!info.is_firmware()).then(|| info.csr()),
+            mask: if info.is_firmware() || info.width() >= 64 {
+                u64::MAX
+            } else {
+                (1 << info.width()) - 1
+            },
+        };
+        if ok(sbi_rt::pmu_counter_start(idx, 1, 0, 0)).is_none() {
+            counter.release();
+            return None;
+        }
+        Some(counter)
+    }
+
+    /// 读计数值。
+    #[inline]
+    fn read(&self) -> u64 {
+        match (self.csr, self.idx) {
+            (Some(csr), _) => read_csr(csr),
+            (None, Some(idx)) => ok(sbi_rt::pmu_counter_fw_read(idx)).unwrap_or(0) as _,
+            (None, None) => unreachable!(),
+        }
+    }
+
+    /// 停止计数并释放计数器。
+    fn release(self) {
+        if let Some(idx) = self.idx {
+            let _ = sbi_rt::pmu_counter_stop(idx, 1, STOP_FLAG_RESET);
+        }
+    }
+}
+
+/// 读计数器 CSR。
+fn read_csr(csr: usize) -> u64 {
+    macro_rules! csrr {
+        ($($offset:literal $name:literal)*) => {
+            match csr.wrapping_sub(0xc00) {
+                $(
+                    $offset => {
+                        let value: usize;
+                        unsafe { core::arch::asm!(concat!("csrr {}, ", $name), out(reg) value) };
+                        value as _
+                    }
+                )*
+                _ => panic!("{csr:#x} is not a counter csr"),
+            }
+        };
+    }
+    csrr! {
+        0 "cycle"           1 "time"            2 "instret"         3 "hpmcounter3"
+        4 "hpmcounter4"     5 "hpmcounter5"     6 "hpmcounter6"     7 "hpmcounter7"
+        8 "hpmcounter8"     9 "hpmcounter9"     10 "hpmcounter10"   11 "hpmcounter11"
+        12 "hpmcounter12"   13 "hpmcounter13"   14 "hpmcounter14"   15 "hpmcounter15"
+        16 "hpmcounter16"   17 "hpmcounter17"   18 "hpmcounter18"   19 "hpmcounter19"
+        20 "hpmcounter20"   21 "hpmcounter21"   22 "hpmcounter22"   23 "hpmcounter23"
+        24 "hpmcounter24"   25 "hpmcounter25"   26 "hpmcounter26"   27 "hpmcounter27"
+        28 "hpmcounter28"   29 "hpmcounter29"   30 "hpmcounter30"   31 "hpmcounter31"
+    }
+}
+
+/// 一组计数器，从建立开始计数，[`finish`](Self::finish) 或释放时停止。
+pub(crate) struct CounterGroup {
+    cycles: Counter,
+    instret: Counter,
+    events: [(Event, Option<Counter>); MAX_EVENTS],
+    len: usize,
+    /// 按 `cycles`、`instret`、`events` 排列的初始值。
+    start: [u64; MAX_EVENTS + 2],
+}
+
+/// 一次测量的结果。
+#[derive(Clone, Copy)]
+pub(crate) struct Sample {
+    /// 周期数。
+    pub cycles: u64,
+    /// 指令数。
+    pub instret: u64,
+    events: [(Event, Option<u64>); MAX_EVENTS],
+    len: usize,
+}
+
+impl CounterGroup {
+    /// 开始测量周期数、指令数和 `events`，不支持的事件结果为 `None`。
+    ///
+    /// 超过 [`MAX_EVENTS`] 的事件被忽略。
+    pub fn new(events: &[Event]) -> Self {
+        let pmu = PMU.get().and_then(Option::as_ref);
+        let configure = |event| pmu.and_then(|pmu| Counter::configure(pmu, event));
+        let mut group = Self {
+            cycles: configure(Event::CYCLES).unwrap_or(Counter::RDCYCLE),
+            instret: configure(Event::INSTRUCTIONS).unwrap_or(Counter::RDINSTRET),
+            events: [(); MAX_EVENTS].map(|_| (Event::CYCLES, None)),
+            len: events.len().min(MAX_EVENTS),
+            start: [0; MAX_EVENTS + 2],
+        };
+        if events.len() > MAX_EVENTS {
+            log::warn!("at most {MAX_EVENTS} events in a group, the rest are ignored");
+        }
+        for (slot, event) in group.events.iter_mut().zip(events) {
+            *slot = (*event, configure(*event));
+        }
+        // 最后读周期数，使测量范围尽量小
+        for (i, (_, counter)) in group.events[..group.len].iter().enumerate() {
+            group.start[i + 2] = counter.as_ref().map_or(0, Counter::read);
+        }
+        group.start[1] = group.instret.read();
+        group.start[0] = group.cycles.read();
+        group
+    }
+
+    /// 在 `events` 的测量下运行 `f`。
+    pub fn measure<T>(events: &[Event], f: impl FnOnce() -> T) -> (T, Sample) {
+        let group = Self::new(events);
+        let ret = f();
+        (ret, group.finish())
+    }
+
+    /// 停止测量并返回结果。
+    pub fn finish(mut self) -> Sample {
+        let sample = self.read();
+        self.release();
+        sample
+    }
+
+    /// 从开始到现在的计数，不停止测量。
+    pub fn read(&self) -> Sample {
+        // 先读周期数，与开始时的顺序相反
+        let cycles = self.cycles.read();
+        let instret = self.instret.read();
+        let mut sample = Sample {
+            cycles: cycles.wrapping_sub(self.start[0]) & self.cycles.mask,
+            instret: instret.wrapping_sub(self.start[1]) & self.instret.mask,
+            events: [(Event::CYCLES, None); MAX_EVENTS],
+            len: self.len,
+        };
+        for (i, (event, counter)) in self.events[..self.len].iter().enumerate() {
+            sample.events[i] = (
+                *event,
+                counter
+                    .as_ref()
+                    .map(|c| c.read().wrapping_sub(self.start[i + 2]) & c.mask),
+            );
+        }
+        sample
+    }
+
+    fn release(&mut self) {
+        let cycles = core::mem::replace(&mut self.cycles, Counter::RDCYCLE);
+        let instret = core::mem::replace(&mut self.instret, Counter::RDINSTRET);
+        cycles.release();
+        instret.release();
+        for (_, counter) in &mut self.events[..self.len] {
+            if let Some(counter) = counter.take() {
+                counter.release();
+            }
+        }
+    }
+}
+
+impl Drop for CounterGroup {
+    #[inline]
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+impl Sample {
+    /// 各个事件的计数，不支持的事件为 `None`。
+    #[inline]
+    pub fn events(&self) -> &[(Event, Option<u64>)] {
+        &self.events[..self.len]
+    }
+}
+
+/// 输出形如 `cycles=1234 instret=567 cache-misses=8 branch-misses=n/a`。
+impl fmt::Display for Sample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cycles={} instret={}", self.cycles, self.instret)?;
+        for (event, value) in self.events() {
+            match value {
+                Some(value) => write!(f, " {}={value}", event.name())?,
+                None => write!(f, " {}=n/a", event.name())?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+﻿use crate::layout::KernelLayout;
+
+/// `R_RISCV_RELATIVE` 重定位类型。
+const R_RISCV_RELATIVE: usize = 3;
+
+/// ELF64 带加数的重定位项。
+#[repr(C)]
+struct Rela {
+    offset: usize,
+    info: usize,
+    addend: usize,
+}
+
+/// 按照 `.rela.dyn` 中的 `R_RISCV_RELATIVE` 项，把内核镜像中的绝对地址修正到运行时的虚地址。
+///
+/// 返回修正的项数。
+///
+/// # Safety
+///
+/// 在物理地址空间中调用，必须在任何经过全局偏移表或读取静态指针的代码之前执行，且只能执行一次。
+pub(crate) unsafe fn relocate(layout: &KernelLayout) -> usize {
+    let start: usize;
+    let end: usize;
+    core::arch::asm!(
+        "lla {0}, _rela_start",
+        "lla {1}, _rela_end",
+        out(reg) start,
+        out(reg) end,
+    );
+    let bias = layout.bias();
+    let relas = core::slice::from_raw_parts(
+        start as *const Rela,
+        (end - start) / core::mem::size_of::<Rela>(),
+    );
+    let mut count = 0;
+    for rela in relas.iter().filter(|r| r.info & 0xffff_ffff == R_RISCV_RELATIVE) {
+        let target = layout.v_to_p(rela.offset.wrapping_add(bias)) as *mut usize;
+        *target = rela.addend.wrapping_add(bias);
+        count += 1;
+    }
+    count
+}
@@ -1,62 +1,1174 @@
-﻿use crate::{non_null, LAYOUT};
-use core::{fmt, ptr::NonNull};
+﻿use crate::{
+    asid::{self, AsidTag},
+    boot::{PagingMode, GIGA},
+    frame, hart, heap, non_null, page,
+    param::Param,
+    trap::{self, Access, Cause, Segments, TrapContext},
+    Global, LAYOUT,
+};
+use alloc::vec::Vec;
+use core::{
+    fmt,
+    ops::Range,
+    ptr::NonNull,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use page_table::{PageTable, PageTableFormatter, Pte, VAddr, VmFlags, VmMeta, PPN, VPN};
-use rangemap::RangeSet;
+use rangemap::{RangeMap, RangeSet};
+use riscv::register::satp;
+use spin::{Mutex, Once};
+
+param! {
+    /// 启动时是否检查地址空间销毁后页帧全部归还。
+    pub(crate) static TEARDOWN_CHECK: Param<bool> =
+        Param::new("teardown-check", "启动时检查地址空间销毁后页帧全部归还", false);
+}
 
 pub(crate) struct AddressSpace<Meta: VmMeta, M: PageManager<Meta>> {
-    segments: RangeSet<VPN<Meta>>,
+    /// 虚存区域，按需映射的区域在第一次访问时才填写页表。
+    segments: RangeMap<VPN<Meta>, Vma<Meta>>,
+    /// 由 [`map`](Self::map) 或 [`map_owned`](Self::map_owned) 映射、属于这个地址空间的页。
+    owned: RangeSet<VPN<Meta>>,
+    /// 为写时复制去掉了写权限的页，第一次写时复制页帧并恢复写权限。
+    cow: RangeSet<VPN<Meta>>,
     root: NonNull<Pte<Meta>>,
+    /// 与内核地址空间共用的根页表项，不属于这个地址空间。
+    shared: Range<usize>,
     manager: M,
+    asid: AsidTag,
+    /// 在缺页处理中填写页表项的次数，包括按需分配和写时复制。
+    minor_faults: usize,
+}
+
+/// 虚存区域：一段虚页的标志和后备存储。
+pub(crate) struct Vma<Meta: VmMeta> {
+    pub flags: VmFlags<Meta>,
+    pub backing: Backing,
+}
+
+impl<Meta: VmMeta> Clone for Vma<Meta> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Meta: VmMeta> Copy for Vma<Meta> {}
+
+impl<Meta: VmMeta> PartialEq for Vma<Meta> {
+    fn eq(&self, other: &Self) -> bool {
+        self.flags == other.flags && self.backing == other.backing
+    }
+}
+
+impl<Meta: VmMeta> Eq for Vma<Meta> {}
+
+/// 虚存区域的后备存储，决定第一次访问时映射什么。
+///
+/// 物理页号按与虚页号的差保存，区域被拆分后仍然有效，物理上连续的相邻区域可以合并。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Backing {
+    /// 分配清零的页帧。
+    Anonymous,
+    /// 直接映射固定的物理页，不分配。
+    Fixed(usize),
+    /// 分配页帧并从物理内存中的文件（如 initrd）复制内容，`end` 是文件末尾的物理地址，之后的部分清零。
+    File { delta: usize, end: usize },
 }
 
+impl Backing {
+    /// 从虚页 `vpn` 开始映射到物理页 `ppn` 开始的连续物理页。
+    pub fn fixed<Meta: VmMeta>(vpn: VPN<Meta>, ppn: PPN<Meta>) -> Self {
+        Self::Fixed(ppn.val().wrapping_sub(vpn.val()))
+    }
+
+    /// 从虚页 `vpn` 开始复制物理页 `ppn` 开始、长 `len` 字节的文件内容。
+    pub fn file<Meta: VmMeta>(vpn: VPN<Meta>, ppn: PPN<Meta>, len: usize) -> Self {
+        Self::File {
+            delta: ppn.val().wrapping_sub(vpn.val()),
+            end: (ppn.val() << Meta::PAGE_BITS) + len,
+        }
+    }
+
+    /// 虚页 `vpn` 对应的物理页，匿名区域没有。
+    fn source<Meta: VmMeta>(self, vpn: VPN<Meta>) -> Option<PPN<Meta>> {
+        match self {
+            Self::Anonymous => None,
+            Self::Fixed(delta) | Self::File { delta, .. } => {
+                Some(PPN::new(vpn.val().wrapping_add(delta)))
+            }
+        }
+    }
+}
+
+/// 内核地址空间的根页表，用户地址空间从这里复制内核映射。
+static KERNEL_ROOT: Once<usize> = Once::new();
+
+/// 建立映射时选择页大小的策略。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum PagePolicy {
+    /// 只使用 4 KiB 页。
+    Small,
+    /// 虚页号、物理页号和长度都对齐时使用 2 MiB 或 1 GiB 大页。
+    Huge,
+    /// 同 [`Huge`](Self::Huge)，修改后再把能合并的页合并为大页。
+    Coalesce,
+}
+
+/// 页表的最大级数。
+const MAX_DEPTH: usize = 5;
+
+/// 一次修改超过这么多页时刷新整个地址空间，而不是逐页刷新。
+const FLUSH_ALL_PAGES: usize = 64;
+
 impl<Meta: VmMeta, M: PageManager<Meta>> AddressSpace<Meta, M> {
+    /// 可写标志。
+    fn writable() -> VmFlags<Meta> {
+        VmFlags::build_from_str("_____W__")
+    }
+
+    /// 可执行标志。
+    fn executable() -> VmFlags<Meta> {
+        VmFlags::build_from_str("____X___")
+    }
+
+    /// 一个页表的项数。
+    const ENTRIES: usize = (1 << Meta::PAGE_BITS) / core::mem::size_of::<Pte<Meta>>();
+
     pub fn new(mut manager: M) -> Self {
-        let ppn = manager.allocate(VmFlags::VALID, 1).ppn();
+        let root = Self::allocate_table(&mut manager);
         Self {
-            segments: RangeSet::new(),
-            root: manager.p_to_v(ppn),
+            segments: RangeMap::new(),
+            owned: RangeSet::new(),
+            cow: RangeSet::new(),
+            root: manager.p_to_v(root.ppn()),
+            shared: 0..0,
             manager,
+            asid: AsidTag::default(),
+            minor_faults: 0,
         }
     }
 
+    /// 建立一个用户地址空间，与内核地址空间共用内核线性段的根页表项。
+    ///
+    /// 内核映射没有 `U` 标志，用户态不能访问；陷入内核后不需要切换地址空间。
+    /// 内核线性段记录为固定映射，标志由内核地址空间决定。
+    pub fn user(manager: M) -> Self {
+        let kernel = *KERNEL_ROOT
+            .get()
+            .expect("kernel address space is not registered");
+        let linear = Self::linear();
+        let start = linear.start.index_in(Meta::MAX_LEVEL);
+        let end = VPN::<Meta>::new(linear.end.val() - 1).index_in(Meta::MAX_LEVEL) + 1;
+        let mut space = Self::new(manager);
+        space.share_roots(non_null(kernel), start..end);
+        let vma = Vma {
+            flags: VmFlags::VALID,
+            backing: Backing::fixed(linear.start, PPN::new(0)),
+        };
+        space.segments.insert(linear, vma);
+        space
+    }
+
+    /// 把这个地址空间登记为内核地址空间，之后建立的用户地址空间共用它的内核映射。
+    pub fn register_kernel(&self) {
+        KERNEL_ROOT.call_once(|| self.root.as_ptr() as usize);
+    }
+
     pub fn root_ppn(&self) -> PPN<Meta> {
         self.manager.v_to_p(self.root)
     }
 
+    /// 页管理器。
+    pub fn manager(&mut self) -> &mut M {
+        &mut self.manager
+    }
+
+    /// 判断虚页范围 `range` 是否与已有的段重叠。
+    pub fn overlaps(&self, range: &Range<VPN<Meta>>) -> bool {
+        self.segments
+            .iter()
+            .any(|(seg, _)| seg.start < range.end && range.start < seg.end)
+    }
+
+    /// 在缺页处理中填写页表项的次数。
+    pub fn minor_faults(&self) -> usize {
+        self.minor_faults
+    }
+
+    /// 内核线性段，从物理地址 0 开始。
+    fn linear() -> Range<VPN<Meta>> {
+        let info = unsafe { &LAYOUT };
+        VAddr::<Meta>::new(info.offset()).floor()..VAddr::<Meta>::new(info.top()).ceil()
+    }
+
+    /// 映射内核线性段，权限取自 `flags`，按区域去掉写或执行权限。
+    ///
+    /// 线性区可读写、不可执行；内核镜像在 4 KiB 粒度上按段映射，代码段可读可执行，只读数据段只读。
     pub fn kernel(&mut self, flags: VmFlags<Meta>) {
         let info = unsafe { &LAYOUT };
-        let top_entries = 1 << Meta::LEVEL_BITS.last().unwrap();
-        let ppn_bits = Meta::pages_in_table(Meta::MAX_LEVEL - 1).trailing_zeros();
+        let text = flags & !Self::writable();
+        let rodata = text & !Self::executable();
+        let flags = flags & !Self::executable();
         // 内核线性段
-        self.segments.insert(
-            VAddr::<Meta>::new(info.offset()).floor()..VAddr::<Meta>::new(info.top()).ceil(),
+        let linear = Self::linear();
+        let backing = Backing::fixed(linear.start, PPN::new(0));
+        self.segments.insert(linear, Vma { flags, backing });
+        // 页表，跳过禁止映射的保留区，其余部分尽量用大页，在保留区周围拆成小页
+        let mut mapped = RangeSet::new();
+        mapped.insert(0..Self::linear_pages());
+        for r in page::reserved().iter().filter(|r| r.no_map) {
+            mapped.remove(r.range.start >> Meta::PAGE_BITS..r.range.end >> Meta::PAGE_BITS);
+        }
+        let vpn = |ppn: usize| VAddr::<Meta>::new(info.p_to_v(ppn << Meta::PAGE_BITS)).floor();
+        for r in mapped.iter() {
+            let range = vpn(r.start)..vpn(r.end);
+            self.fill(range, PPN::new(r.start), flags, PagePolicy::Huge);
+        }
+        // 内核镜像，拆开所在的大页
+        let pages = |r: Range<usize>| VAddr::new(r.start).floor()..VAddr::new(r.end).ceil();
+        self.protect(pages(info.text()), text, PagePolicy::Small);
+        self.protect(pages(info.rodata()), rodata, PagePolicy::Small);
+    }
+
+    /// 线性区映射的物理页数，按 1 GiB 向上取整。
+    fn linear_pages() -> usize {
+        let info = unsafe { &LAYOUT };
+        let giga = Self::leaf_pages(GIGA);
+        let pages = VAddr::<Meta>::new(info.v_to_p(info.top())).ceil().val();
+        (pages + giga - 1) & !(giga - 1)
+    }
+
+    /// [`kernel`](Self::kernel) 最多分配的页表数，不含根页表。
+    ///
+    /// 线性区用 1 GiB 大页映射，需要 1 GiB 以上各级的页表；
+    /// 禁止映射的保留区和内核镜像的代码段、只读数据段，每个的两端最多各把一个 1 GiB 大页拆到 4 KiB。
+    pub fn kernel_tables() -> usize {
+        let pages = Self::linear_pages();
+        let linear = (GIGA..Meta::MAX_LEVEL)
+            .map(|level| (pages + Meta::pages_in_table(level) - 1) / Meta::pages_in_table(level))
+            .sum::<usize>();
+        let no_map = page::reserved().iter().filter(|r| r.no_map).count();
+        linear + 2 * (no_map + 2) * GIGA
+    }
+
+    /// 线性区中代码段以外第一个可执行的虚地址。
+    fn linear_executable(&self) -> Option<usize> {
+        let info = unsafe { &LAYOUT };
+        let text = info.text();
+        let text = VAddr::<Meta>::new(text.start).floor()..VAddr::<Meta>::new(text.end).ceil();
+        let linear = Self::linear();
+        let mut vpn = linear.start.val();
+        while vpn < linear.end.val() {
+            let (_, level, pte) = self.walk(VPN::new(vpn), 0);
+            let pages = Self::leaf_pages(level);
+            let start = vpn & !(pages - 1);
+            vpn = start + pages;
+            let leaf = VPN::<Meta>::new(start);
+            let executable = pte.is_valid() && pte.flags().contains(Self::executable());
+            if executable && !text.contains(&leaf) {
+                return Some(leaf.base().val());
+            }
+        }
+        None
+    }
+
+    /// 分配清零的页帧并映射虚页范围 `range`，页帧属于这个地址空间，取消映射时还给页管理器。
+    pub fn map(&mut self, range: Range<VPN<Meta>>, flags: VmFlags<Meta>, policy: PagePolicy) {
+        let len = range.end.val() - range.start.val();
+        if len == 0 {
+            return;
+        }
+        // 先检查再分配，重叠时不浪费页帧
+        self.check_free(&range);
+        let ppn = self.manager.allocate(VmFlags::VALID, len).ppn();
+        let ptr = self.manager.p_to_v::<u8>(ppn);
+        unsafe { core::ptr::write_bytes(ptr.as_ptr(), 0, len << Meta::PAGE_BITS) };
+        self.map_owned(range, ppn, flags, policy);
+    }
+
+    /// 把虚页范围 `range` 映射到从 `ppn` 开始的连续物理页，物理页必须是从这个地址空间的页管理器分配的。
+    ///
+    /// 与 [`map`](Self::map) 一样，页帧属于这个地址空间，取消映射时还给页管理器。
+    pub fn map_owned(
+        &mut self,
+        range: Range<VPN<Meta>>,
+        ppn: PPN<Meta>,
+        flags: VmFlags<Meta>,
+        policy: PagePolicy,
+    ) {
+        self.owned.insert(range.clone());
+        let backing = Backing::Anonymous;
+        self.populate(range, ppn, Vma { flags, backing }, policy);
+    }
+
+    /// 把虚页范围 `range` 映射到从 `ppn` 开始的连续物理页。
+    ///
+    /// 物理页不属于这个地址空间，取消映射时不释放。
+    pub fn map_to(
+        &mut self,
+        range: Range<VPN<Meta>>,
+        ppn: PPN<Meta>,
+        flags: VmFlags<Meta>,
+        policy: PagePolicy,
+    ) {
+        let backing = Backing::fixed(range.start, ppn);
+        self.populate(range, ppn, Vma { flags, backing }, policy);
+    }
+
+    /// 只记录虚页范围 `range` 的虚存区域，不填写页表，第一次访问时在缺页处理中按 `backing` 映射一页。
+    pub fn map_lazy(&mut self, range: Range<VPN<Meta>>, flags: VmFlags<Meta>, backing: Backing) {
+        self.check_free(&range);
+        if !range.is_empty() {
+            self.segments.insert(range, Vma { flags, backing });
+        }
+    }
+
+    /// 断言虚页范围 `range` 与已有的段不重叠。
+    fn check_free(&self, range: &Range<VPN<Meta>>) {
+        assert!(
+            !self.overlaps(range),
+            "{:#x}..{:#x} is already mapped",
+            range.start.base().val(),
+            range.end.base().val(),
+        );
+    }
+
+    /// 断言虚页范围 `range` 不涉及与内核共用的根页表项，这些页表不属于这个地址空间。
+    fn check_private(&self, range: &Range<VPN<Meta>>) {
+        let first = range.start.index_in(Meta::MAX_LEVEL);
+        let last = VPN::<Meta>::new(range.end.val() - 1).index_in(Meta::MAX_LEVEL);
+        assert!(
+            last < self.shared.start || self.shared.end <= first,
+            "{:#x}..{:#x} overlaps the shared kernel mappings",
+            range.start.base().val(),
+            range.end.base().val(),
+        );
+    }
+
+    /// 记录虚存区域 `vma` 并把虚页范围 `range` 映射到从 `ppn` 开始的连续物理页。
+    fn populate(
+        &mut self,
+        range: Range<VPN<Meta>>,
+        ppn: PPN<Meta>,
+        vma: Vma<Meta>,
+        policy: PagePolicy,
+    ) {
+        self.check_free(&range);
+        self.fill(range.clone(), ppn, vma.flags, policy);
+        self.segments.insert(range.clone(), vma);
+        if policy == PagePolicy::Coalesce {
+            // 和相邻的页一起合并
+            let giga = Self::leaf_pages(GIGA);
+            let start = range.start.val() & !(giga - 1);
+            let end = (range.end.val() + giga - 1) & !(giga - 1);
+            self.coalesce(VPN::new(start)..VPN::new(end));
+        }
+        self.flush(Some(range));
+    }
+
+    /// 取消虚页范围 `range` 的映射，范围内没有映射的页忽略。
+    ///
+    /// 属于这个地址空间的页帧还给页管理器，变空的中间页表也一并回收。
+    /// 只有一部分在范围内的大页先拆分。范围不能涉及与内核共用的根页表项。
+    pub fn unmap(&mut self, range: Range<VPN<Meta>>) {
+        if range.is_empty() {
+            return;
+        }
+        self.check_private(&range);
+        let end = range.end.val();
+        let mut tables_freed = false;
+        let mut vpn = range.start.val();
+        while vpn < end {
+            let page = VPN::<Meta>::new(vpn);
+            let (tables, level, pte) = self.walk(page, 0);
+            let pages = Self::leaf_pages(level);
+            if !pte.is_valid() {
+                vpn = (vpn & !(pages - 1)) + pages;
+                continue;
+            }
+            if vpn & (pages - 1) != 0 || end - vpn < pages {
+                self.split(tables[level], page, level);
+                continue;
+            }
+            if self.owned.contains(&page) {
+                self.manager.deallocate(pte, pages);
+            }
+            unsafe { clear_entry(tables[level], page.index_in(level)) };
+            // 从下往上回收空的中间页表，根页表保留
+            for level in level..Meta::MAX_LEVEL {
+                if !is_empty(tables[level]) {
+                    break;
+                }
+                let parent = unsafe { table_entry(tables[level + 1], page.index_in(level + 1)) };
+                self.manager.deallocate(parent, 1);
+                unsafe { clear_entry(tables[level + 1], page.index_in(level + 1)) };
+                tables_freed = true;
+            }
+            vpn += pages;
+        }
+        self.segments.remove(range.clone());
+        self.owned.remove(range.clone());
+        self.cow.remove(range.clone());
+        // 指定地址的 sfence.vma 不保证刷新缓存的中间页表项
+        self.flush(if tables_freed { None } else { Some(range) });
+    }
+
+    /// 把虚页范围 `range` 中每一页的标志改为 `flags`，映射的物理页不变。
+    ///
+    /// 只有一部分在范围内的大页先拆分，`policy` 为 [`PagePolicy::Coalesce`] 时修改后重新合并。
+    /// 属于这个地址空间的页变为可写时，与其他地址空间共享的页帧先复制一份。
+    /// 还没有访问过的页只修改虚存区域的标志。
+    pub fn protect(&mut self, range: Range<VPN<Meta>>, flags: VmFlags<Meta>, policy: PagePolicy) {
+        if let Some(gap) = self.segments.gaps(&range).next() {
+            panic!("{:#x} is not mapped", gap.start.base().val());
+        }
+        let vmas = self
+            .segments
+            .iter()
+            .filter(|(seg, _)| seg.start < range.end && range.start < seg.end)
+            .map(|(seg, vma)| (seg.start.max(range.start)..seg.end.min(range.end), *vma))
+            .collect::<Vec<_>>();
+        for (seg, vma) in vmas {
+            self.segments.insert(seg, Vma { flags, ..vma });
+        }
+        let end = range.end.val();
+        let mut vpn = range.start.val();
+        while vpn < end {
+            let (tables, level, pte) = self.walk(VPN::new(vpn), 0);
+            let pages = Self::leaf_pages(level);
+            if !pte.is_valid() {
+                vpn = (vpn & !(pages - 1)) + pages;
+                continue;
+            }
+            if vpn & (pages - 1) != 0 || end - vpn < pages {
+                self.split(tables[level], VPN::new(vpn), level);
+                continue;
+            }
+            let page = VPN::<Meta>::new(vpn);
+            let pte = if flags.contains(Self::writable()) && self.owned.contains(&page) {
+                self.manager.exclude(pte, pages)
+            } else {
+                pte
+            };
+            let index = page.index_in(level);
+            unsafe { set_entry(tables[level], index, flags.build_pte(pte.ppn())) };
+            vpn += pages;
+        }
+        self.cow.remove(range.clone());
+        if policy == PagePolicy::Coalesce {
+            self.coalesce(range.clone());
+        }
+        self.flush(Some(range));
+    }
+
+    /// 把虚页范围 `range` 中能合并的页合并为大页。
+    ///
+    /// 一个完全在范围内的页表，所有项都是标志相同、物理上连续且按大页对齐的叶子时，换成一个大页。
+    pub fn coalesce(&mut self, range: Range<VPN<Meta>>) {
+        let mut merged = false;
+        for level in 1..=GIGA.min(Meta::MAX_LEVEL) {
+            let pages = Self::leaf_pages(level);
+            let step = Self::leaf_pages(level - 1);
+            let mut vpn = (range.start.val() + pages - 1) & !(pages - 1);
+            while vpn + pages <= range.end.val() {
+                let block = VPN::new(vpn)..VPN::new(vpn + pages);
+                vpn += pages;
+                let (tables, l, pte) = self.walk(block.start, level);
+                if l != level || !pte.is_valid() || pte.is_leaf() || !self.same_owner(&block) {
+                    continue;
+                }
+                let table = self.manager.p_to_v::<Pte<Meta>>(pte.ppn());
+                let first = unsafe { table_entry(table, 0) };
+                let mergeable = first.ppn().val() & (pages - 1) == 0
+                    && (0..Self::ENTRIES).all(|i| {
+                        let entry = unsafe { table_entry(table, i) };
+                        entry.is_valid()
+                            && entry.is_leaf()
+                            && entry.flags() == first.flags()
+                            && entry.ppn().val() == first.ppn().val() + i * step
+                    });
+                if mergeable {
+                    let index = block.start.index_in(level);
+                    unsafe { set_entry(tables[level], index, first) };
+                    self.manager.deallocate(pte, 1);
+                    merged = true;
+                }
+            }
+        }
+        if merged {
+            self.flush(None);
+        }
+    }
+
+    /// 复制这个地址空间，新的地址空间使用页管理器 `manager`。
+    ///
+    /// 属于这个地址空间的页，`cow` 为 `false` 时立即复制页帧；
+    /// 为 `true` 时两边共享页帧并去掉写权限，第一次写时在缺页处理中复制。
+    /// 其他映射（内核线性区、固定映射）直接共享。
+    pub fn fork(&mut self, manager: M, cow: bool) -> Self {
+        let mut child = Self::new(manager);
+        child.share_roots(self.root, self.shared.clone());
+        child.segments = self.segments.clone();
+        child.owned = self.owned.clone();
+        child.cow = self.cow.clone();
+        let mut write_protected = false;
+        for (seg, _) in self.segments.clone().iter() {
+            if self.shared.contains(&seg.start.index_in(Meta::MAX_LEVEL)) {
+                continue;
+            }
+            let mut vpn = seg.start.val();
+            while vpn < seg.end.val() {
+                let page = VPN::<Meta>::new(vpn);
+                let (tables, level, pte) = self.walk(page, 0);
+                let pages = Self::leaf_pages(level);
+                let start = vpn & !(pages - 1);
+                vpn = start + pages;
+                if !pte.is_valid() {
+                    continue;
+                }
+                let leaf = VPN::new(start)..VPN::new(start + pages);
+                if !self.owned.contains(&page) {
+                    *child.entry_mut(leaf.start, level) = pte;
+                } else if cow {
+                    let (parent, shared) = self.manager.share(pte, pages);
+                    let flags = pte.flags();
+                    if flags.contains(Self::writable()) {
+                        let flags = flags & !Self::writable();
+                        let index = page.index_in(level);
+                        unsafe { set_entry(tables[level], index, flags.build_pte(parent.ppn())) };
+                        *child.entry_mut(leaf.start, level) = flags.build_pte(shared.ppn());
+                        self.cow.insert(leaf.clone());
+                        child.cow.insert(leaf);
+                        write_protected = true;
+                    } else {
+                        *child.entry_mut(leaf.start, level) = shared;
+                    }
+                } else {
+                    let ppn = child.manager.allocate(VmFlags::VALID, pages).ppn();
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(
+                            self.manager.p_to_v::<u8>(pte.ppn()).as_ptr(),
+                            child.manager.p_to_v::<u8>(ppn).as_ptr(),
+                            pages << Meta::PAGE_BITS,
+                        )
+                    };
+                    // 新页帧不一定按大页对齐
+                    child.fill(leaf, ppn, pte.flags(), PagePolicy::Huge);
+                }
+            }
+        }
+        if write_protected {
+            self.flush(None);
+        }
+        child
+    }
+
+    /// 按虚存区域 `vma` 的后备存储映射第一次访问的页 `vpn`。
+    fn fault_in(&mut self, vpn: VPN<Meta>, vma: Vma<Meta>) {
+        let page = vpn..VPN::new(vpn.val() + 1);
+        let source = vma.backing.source(vpn);
+        let ppn = match vma.backing {
+            Backing::Fixed(_) => source.unwrap(),
+            Backing::Anonymous | Backing::File { .. } => {
+                let ppn = self.manager.allocate(VmFlags::VALID, 1).ppn();
+                let dst = self.manager.p_to_v::<u8>(ppn).as_ptr();
+                let len = 1 << Meta::PAGE_BITS;
+                // 文件末尾之后的部分不属于文件，不能复制
+                let copied = match (vma.backing, source) {
+                    (Backing::File { end, .. }, Some(src)) => {
+                        end.saturating_sub(src.val() << Meta::PAGE_BITS).min(len)
+                    }
+                    _ => 0,
+                };
+                unsafe {
+                    if let Some(src) = source.filter(|_| copied > 0) {
+                        let src = self.manager.p_to_v::<u8>(src).as_ptr();
+                        core::ptr::copy_nonoverlapping(src, dst, copied);
+                    }
+                    core::ptr::write_bytes(dst.add(copied), 0, len - copied);
+                }
+                self.owned.insert(page.clone());
+                ppn
+            }
+        };
+        self.fill(page.clone(), ppn, vma.flags, PagePolicy::Small);
+        self.flush(Some(page));
+    }
+
+    /// 为写时复制的页 `vpn` 复制一份私有的页帧并恢复写权限，大页先拆成 4 KiB 页。
+    fn copy_on_write(&mut self, vpn: VPN<Meta>) {
+        let (tables, pte) = loop {
+            let (tables, level, pte) = self.walk(vpn, 0);
+            if level == 0 {
+                break (tables, pte);
+            }
+            self.split(tables[level], vpn, level);
+        };
+        let pte = self.manager.exclude(pte, 1);
+        let flags = pte.flags() | Self::writable();
+        unsafe { set_entry(tables[0], vpn.index_in(0), flags.build_pte(pte.ppn())) };
+        let page = vpn..VPN::new(vpn.val() + 1);
+        self.cow.remove(page.clone());
+        self.flush(Some(page));
+    }
+
+    /// 查询虚地址 `addr` 所在的页映射到的物理页号和标志。
+    pub fn translate(&self, addr: VAddr<Meta>) -> Option<(PPN<Meta>, VmFlags<Meta>)> {
+        let vpn = addr.floor();
+        let (_, level, pte) = self.walk(vpn, 0);
+        if !pte.is_valid() {
+            return None;
+        }
+        // 大页中按偏移找到对应的页
+        let offset = vpn.val() & (Self::leaf_pages(level) - 1);
+        Some((PPN::new(pte.ppn().val() + offset), pte.flags()))
+    }
+
+    /// `level` 级的叶子页表项覆盖的页数。
+    fn leaf_pages(level: usize) -> usize {
+        if level == 0 {
+            1
+        } else {
+            Meta::pages_in_table(level - 1)
+        }
+    }
+
+    /// 判断 `range` 中的页是否全部属于或全部不属于这个地址空间。
+    fn same_owner(&self, range: &Range<VPN<Meta>>) -> bool {
+        match self.owned.get(&range.start) {
+            Some(owned) => owned.end >= range.end,
+            None => !self
+                .owned
+                .iter()
+                .any(|owned| owned.start < range.end && range.start < owned.end),
+        }
+    }
+
+    /// 从根页表沿 `vpn` 向下走到 `stop` 级，遇到叶子或无效项时提前停下。
+    ///
+    /// 返回沿途每一级的页表、停下的级别和那一级的页表项。
+    fn walk(
+        &self,
+        vpn: VPN<Meta>,
+        stop: usize,
+    ) -> ([NonNull<Pte<Meta>>; MAX_DEPTH], usize, Pte<Meta>) {
+        let mut tables = [self.root; MAX_DEPTH];
+        let mut level = Meta::MAX_LEVEL;
+        loop {
+            let pte = unsafe { table_entry(tables[level], vpn.index_in(level)) };
+            if level == stop || !pte.is_valid() || pte.is_leaf() {
+                return (tables, level, pte);
+            }
+            level -= 1;
+            tables[level] = self.manager.p_to_v(pte.ppn());
+        }
+    }
+
+    /// 把 `table` 中映射 `vpn` 的 `level` 级大页拆成下一级的页，映射不变。
+    fn split(&mut self, table: NonNull<Pte<Meta>>, vpn: VPN<Meta>, level: usize) {
+        let index = vpn.index_in(level);
+        let pte = unsafe { table_entry(table, index) };
+        let sub = Self::allocate_table(&mut self.manager);
+        let entries = self.manager.p_to_v::<Pte<Meta>>(sub.ppn());
+        let step = Self::leaf_pages(level - 1);
+        for i in 0..Self::ENTRIES {
+            let ppn = PPN::new(pte.ppn().val() + i * step);
+            unsafe { set_entry(entries, i, pte.flags().build_pte(ppn)) };
+        }
+        unsafe { set_entry(table, index, sub) };
+    }
+
+    /// 按 `policy` 选择页大小，为虚页范围 `range` 填写页表项，不修改段。
+    fn fill(
+        &mut self,
+        range: Range<VPN<Meta>>,
+        ppn: PPN<Meta>,
+        flags: VmFlags<Meta>,
+        policy: PagePolicy,
+    ) {
+        let end = range.end.val();
+        let mut vpn = range.start.val();
+        let mut ppn = ppn.val();
+        while vpn < end {
+            // 虚页号、物理页号和剩余长度都允许的最大页
+            let mut level = match policy {
+                PagePolicy::Small => 0,
+                PagePolicy::Huge | PagePolicy::Coalesce => GIGA.min(Meta::MAX_LEVEL),
+            };
+            while level > 0 {
+                let pages = Self::leaf_pages(level);
+                if vpn & (pages - 1) == 0 && ppn & (pages - 1) == 0 && end - vpn >= pages {
+                    break;
+                }
+                level -= 1;
+            }
+            // 已经有中间页表时改用下一级
+            loop {
+                let entry = self.entry_mut(VPN::new(vpn), level);
+                if !entry.is_valid() {
+                    *entry = flags.build_pte(PPN::new(ppn));
+                    break;
+                }
+                assert!(
+                    level > 0 && !entry.is_leaf(),
+                    "{:#x} is already mapped",
+                    vpn << Meta::PAGE_BITS
+                );
+                level -= 1;
+            }
+            let pages = Self::leaf_pages(level);
+            vpn += pages;
+            ppn += pages;
+        }
+    }
+
+    /// 回收 `level` 级页表 `table` 下的所有中间页表，不回收 `table` 本身，叶子不动。
+    fn free_tables(&mut self, table: NonNull<Pte<Meta>>, level: usize) {
+        if level == 0 {
+            return;
+        }
+        for i in 0..Self::ENTRIES {
+            let pte = unsafe { table_entry(table, i) };
+            if pte.is_valid() && !pte.is_leaf() {
+                self.free_tables(self.manager.p_to_v(pte.ppn()), level - 1);
+                self.manager.deallocate(pte, 1);
+            }
+        }
+    }
+
+    /// 找到 `vpn` 在 `level` 级页表中的页表项，沿途缺少的中间页表从页管理器分配。
+    fn entry_mut(&mut self, vpn: VPN<Meta>, level: usize) -> &mut Pte<Meta> {
+        let mut table = self.root;
+        for l in (level + 1..=Meta::MAX_LEVEL).rev() {
+            let pte = unsafe { &mut *table.as_ptr().add(vpn.index_in(l)) };
+            if !pte.is_valid() {
+                *pte = Self::allocate_table(&mut self.manager);
+            }
+            assert!(
+                !pte.is_leaf(),
+                "{:#x} is mapped by a huge page",
+                vpn.base().val()
+            );
+            table = self.manager.p_to_v(pte.ppn());
+        }
+        unsafe { &mut *table.as_ptr().add(vpn.index_in(level)) }
+    }
+
+    /// 刷新这个地址空间的地址转换缓存，`range` 为 `None` 时刷新整个地址空间。
+    ///
+    /// 地址空间持有当前一代的 ASID 时只刷新这个 ASID，否则当前硬件线程只在它正在使用时刷新。
+    /// 其他已经上线的硬件线程通过 SBI RFENCE 扩展远程刷新，不知道它们是否正在使用这个地址空间，所以总是通知。
+    /// 全局映射只由 [`kernel`](Self::kernel) 建立且不会修改，不需要刷新。
+    fn flush(&self, range: Option<Range<VPN<Meta>>>) {
+        let asid = asid::current(self.asid);
+        let range = range.filter(|r| r.end.val() - r.start.val() <= FLUSH_ALL_PAGES);
+        let others = hart::others();
+        if others != 0 {
+            let (start, size) = match &range {
+                Some(r) => (
+                    r.start.base().val(),
+                    (r.end.val() - r.start.val()) << Meta::PAGE_BITS,
+                ),
+                None => (0, usize::MAX),
+            };
+            let ret = match asid {
+                Some(asid) => sbi_rt::remote_sfence_vma_asid(others, 0, start, size, asid),
+                None => sbi_rt::remote_sfence_vma(others, 0, start, size),
+            };
+            if ret.error != 0 {
+                panic!("remote sfence.vma on harts {others:#x} failed: {ret:?}");
+            }
+        }
+        if asid.is_none() && satp::read().ppn() != self.root_ppn().val() {
+            return;
+        }
+        match range {
+            Some(range) => {
+                for vpn in range.start.val()..range.end.val() {
+                    unsafe { sfence_vma(Some(VPN::<Meta>::new(vpn).base().val()), asid) };
+                }
+            }
+            None => unsafe { sfence_vma(None, asid) },
+        }
+    }
+
+    /// 从根页表 `root` 复制第 `range` 项，这些项指向的页表和页帧不属于这个地址空间。
+    fn share_roots(&mut self, root: NonNull<Pte<Meta>>, range: Range<usize>) {
+        for i in range.clone() {
+            unsafe { set_entry(self.root, i, table_entry(root, i)) };
+        }
+        self.shared = range;
+    }
+
+    /// 分配一个清零的页表页。
+    fn allocate_table(manager: &mut M) -> Pte<Meta> {
+        let pte = manager.allocate(VmFlags::VALID, 1);
+        let table = manager.p_to_v::<u8>(pte.ppn());
+        unsafe { core::ptr::write_bytes(table.as_ptr(), 0, 1 << Meta::PAGE_BITS) };
+        pte
+    }
+}
+
+impl<Meta: PagingMode, M: PageManager<Meta>> AddressSpace<Meta, M> {
+    /// 切换到这个地址空间，必要时分配 ASID 并刷新 TLB。
+    pub fn activate(&mut self) {
+        let (asid, flush) = asid::assign(&mut self.asid);
+        unsafe {
+            satp::set(Meta::MODE, asid, self.root_ppn().val());
+            if flush {
+                riscv::asm::sfence_vma_all();
+            }
+        }
+    }
+}
+
+/// 读取页表 `table` 的第 `index` 项。
+#[inline]
+unsafe fn table_entry<Meta: VmMeta>(table: NonNull<Pte<Meta>>, index: usize) -> Pte<Meta> {
+    *table.as_ptr().add(index)
+}
+
+/// 写入页表 `table` 的第 `index` 项。
+#[inline]
+unsafe fn set_entry<Meta: VmMeta>(table: NonNull<Pte<Meta>>, index: usize, pte: Pte<Meta>) {
+    *table.as_ptr().add(index) = pte
+}
+
+/// 清空页表 `table` 的第 `index` 项。
+#[inline]
+unsafe fn clear_entry<Meta: VmMeta>(table: NonNull<Pte<Meta>>, index: usize) {
+    core::ptr::write_bytes(table.as_ptr().add(index), 0, 1)
+}
+
+/// 判断页表 `table` 是否没有有效项。
+fn is_empty<Meta: VmMeta>(table: NonNull<Pte<Meta>>) -> bool {
+    let len = (1 << Meta::PAGE_BITS) / core::mem::size_of::<Pte<Meta>>();
+    (0..len).all(|i| !unsafe { table_entry(table, i) }.is_valid())
+}
+
+/// 执行 `sfence.vma`，`vaddr` 或 `asid` 为 `None` 时对应的操作数是 `zero`。
+unsafe fn sfence_vma(vaddr: Option<usize>, asid: Option<usize>) {
+    use core::arch::asm;
+    match (vaddr, asid) {
+        (Some(vaddr), Some(asid)) => asm!("sfence.vma {0}, {1}", in(reg) vaddr, in(reg) asid),
+        (Some(vaddr), None) => asm!("sfence.vma {0}, zero", in(reg) vaddr),
+        (None, Some(asid)) => asm!("sfence.vma zero, {0}", in(reg) asid),
+        (None, None) => asm!("sfence.vma"),
+    }
+}
+
+/// 用 4 KiB 页或 2 MiB 大页映射并取消映射 2 MiB。
+fn map_unmap<Meta: PagingMode>(b: &mut crate::bench::Bencher, policy: PagePolicy) {
+    let mut space = AddressSpace::<Meta, Global>::new(Global);
+    let range = VAddr::<Meta>::new(0x4000_0000).floor()..VAddr::new(0x4020_0000).floor();
+    let flags = VmFlags::build_from_str("DA_U_WRV");
+    b.iter(|| {
+        space.map(range.clone(), flags, policy);
+        space.unmap(range.clone());
+    });
+}
+
+bench!(map_unmap_small, |b| match satp::read().mode() {
+    satp::Mode::Sv57 => map_unmap::<page_table::Sv57>(b, PagePolicy::Small),
+    satp::Mode::Sv48 => map_unmap::<page_table::Sv48>(b, PagePolicy::Small),
+    _ => map_unmap::<page_table::Sv39>(b, PagePolicy::Small),
+});
+
+bench!(map_unmap_huge, |b| match satp::read().mode() {
+    satp::Mode::Sv57 => map_unmap::<page_table::Sv57>(b, PagePolicy::Huge),
+    satp::Mode::Sv48 => map_unmap::<page_table::Sv48>(b, PagePolicy::Huge),
+    _ => map_unmap::<page_table::Sv39>(b, PagePolicy::Huge),
+});
+
+/// 复制一个映射了 64 页的地址空间再销毁。
+fn fork_destroy<Meta: PagingMode>(b: &mut crate::bench::Bencher, cow: bool) {
+    let mut parent = AddressSpace::<Meta, Global>::new(Global);
+    let base = VAddr::<Meta>::new(0x4000_0000).floor();
+    let flags = VmFlags::build_from_str("DA_U_WRV");
+    parent.map(base..VPN::new(base.val() + 64), flags, PagePolicy::Small);
+    b.iter(|| parent.fork(Global, cow));
+}
+
+bench!(fork_eager, |b| match satp::read().mode() {
+    satp::Mode::Sv57 => fork_destroy::<page_table::Sv57>(b, false),
+    satp::Mode::Sv48 => fork_destroy::<page_table::Sv48>(b, false),
+    _ => fork_destroy::<page_table::Sv39>(b, false),
+});
+
+bench!(fork_cow, |b| match satp::read().mode() {
+    satp::Mode::Sv57 => fork_destroy::<page_table::Sv57>(b, true),
+    satp::Mode::Sv48 => fork_destroy::<page_table::Sv48>(b, true),
+    _ => fork_destroy::<page_table::Sv39>(b, true),
+});
+
+bench!(cow_fault, |b| match satp::read().mode() {
+    satp::Mode::Sv57 => cow_fault_in::<page_table::Sv57>(b),
+    satp::Mode::Sv48 => cow_fault_in::<page_table::Sv48>(b),
+    _ => cow_fault_in::<page_table::Sv39>(b),
+});
+
+/// 写时复制地址空间，切换过去每次写一个新页，触发一次缺页。
+///
+/// 复制和销毁地址空间都在计时之外，父地址空间为每次迭代映射一页。
+fn cow_fault_in<Meta: PagingMode>(b: &mut crate::bench::Bencher) {
+    let pages = b.iterations();
+    let saved = satp::read().bits();
+    let mut parent = AddressSpace::<Meta, Global>::new(Global);
+    parent.kernel(VmFlags::build_from_str("DA__XWRV"));
+    let base = VAddr::<Meta>::new(0x4000_0000).floor();
+    let flags = VmFlags::build_from_str("DA_U_WRV");
+    parent.map(base..VPN::new(base.val() + pages), flags, PagePolicy::Small);
+    let child = Mutex::new(parent.fork(Global, true));
+    child.lock().activate();
+    let mut next = base.val();
+    crate::trap::with_space(&child, || {
+        b.iter(|| {
+            let addr = VPN::<Meta>::new(next).base().val();
+            unsafe { (addr as *mut u8).write_volatile(1) };
+            next += 1;
+        })
+    });
+    assert_eq!(child.lock().minor_faults(), pages);
+    unsafe {
+        core::arch::asm!("csrw satp, {0}", in(reg) saved);
+        riscv::asm::sfence_vma_all();
+    }
+}
+
+bench!(populate_eager, |b| match satp::read().mode() {
+    satp::Mode::Sv57 => populate::<page_table::Sv57>(b, false),
+    satp::Mode::Sv48 => populate::<page_table::Sv48>(b, false),
+    _ => populate::<page_table::Sv39>(b, false),
+});
+
+bench!(populate_lazy, |b| match satp::read().mode() {
+    satp::Mode::Sv57 => populate::<page_table::Sv57>(b, true),
+    satp::Mode::Sv48 => populate::<page_table::Sv48>(b, true),
+    _ => populate::<page_table::Sv39>(b, true),
+});
+
+/// 在用户地址空间中映射 64 页并逐页写一次再取消映射，`lazy` 时每页触发一次缺页。
+fn populate<Meta: PagingMode>(b: &mut crate::bench::Bencher, lazy: bool) {
+    const PAGES: usize = 64;
+    let saved = satp::read().bits();
+    let space = Mutex::new(AddressSpace::<Meta, Global>::user(Global));
+    let base = VAddr::<Meta>::new(0x4000_0000).floor();
+    let range = base..VPN::new(base.val() + PAGES);
+    let flags = VmFlags::build_from_str("DA_U_WRV");
+    space.lock().activate();
+    crate::trap::with_space(&space, || {
+        b.iter(|| {
+            {
+                let mut space = space.lock();
+                if lazy {
+                    space.map_lazy(range.clone(), flags, Backing::Anonymous);
+                } else {
+                    space.map(range.clone(), flags, PagePolicy::Small);
+                }
+            }
+            for i in 0..PAGES {
+                let addr = VPN::<Meta>::new(base.val() + i).base().val();
+                unsafe { (addr as *mut u8).write_volatile(1) };
+            }
+            space.lock().unmap(range.clone());
+        })
+    });
+    log::debug!("populate: {} minor faults", space.lock().minor_faults());
+    unsafe {
+        core::arch::asm!("csrw satp, {0}", in(reg) saved);
+        riscv::asm::sfence_vma_all();
+    }
+}
+
+impl<Meta: VmMeta, M: PageManager<Meta>> Drop for AddressSpace<Meta, M> {
+    /// 回收属于这个地址空间的页帧和所有页表页，不属于它的页帧（内核线性区、固定映射）不动。
+    ///
+    /// 与内核地址空间共用的页表不回收。
+    fn drop(&mut self) {
+        assert!(
+            satp::read().ppn() != self.root_ppn().val(),
+            "dropping the active address space"
         );
-        // 页表
-        unsafe { core::slice::from_raw_parts_mut(self.root.as_ptr(), top_entries) }
-            .iter_mut()
-            .skip(
-                VAddr::<Meta>::new(info.offset())
-                    .floor()
-                    .index_in(Meta::MAX_LEVEL),
-            )
-            .take(
-                VAddr::<Meta>::new(info.v_to_p(info.top()))
-                    .ceil()
-                    .ceil(Meta::MAX_LEVEL),
-            )
-            .enumerate()
-            .for_each(|(i, pte)| *pte = flags.build_pte(PPN::new(i << ppn_bits)));
+        while let Some(range) = self.owned.iter().next().cloned() {
+            self.unmap(range);
+        }
+        for i in self.shared.clone() {
+            unsafe { clear_entry(self.root, i) };
+        }
+        self.free_tables(self.root, Meta::MAX_LEVEL);
+        let root = VmFlags::VALID.build_pte(self.root_ppn());
+        self.manager.deallocate(root, 1);
+    }
+}
+
+/// 建立一个地址空间，映射、拆分、部分取消映射、按需映射、复制后全部销毁，检查页帧全部还给页帧分配器。
+///
+/// 堆从页帧分配器取走的内存不会归还，比较空闲内存时加回这部分。
+pub(crate) fn teardown_check<Meta: VmMeta>() {
+    let free = || unsafe { page::GLOBAL.free() } + heap::grown();
+    let before = (frame::in_use(), free());
+    {
+        let mut space = AddressSpace::<Meta, Global>::new(Global);
+        space.kernel(VmFlags::build_from_str("DA__XWRV"));
+        let mega = AddressSpace::<Meta, Global>::leaf_pages(1);
+        let base = VAddr::<Meta>::new(0x4000_0000).floor().val();
+        let range = |start: usize, end: usize| VPN::new(base + start)..VPN::new(base + end);
+        let rw = VmFlags::build_from_str("DA_U_WRV");
+        let ro = VmFlags::build_from_str("DA_U__RV");
+        space.map(range(0, 2 * mega + 3), rw, PagePolicy::Huge);
+        space.protect(range(1, 2), ro, PagePolicy::Small);
+        space.unmap(range(mega, mega + 5));
+        // 按需映射的匿名页和从内核镜像复制的页
+        space.map_lazy(range(4 * mega, 4 * mega + 8), rw, Backing::Anonymous);
+        let image = unsafe { LAYOUT.start() };
+        let ppn = PPN::new(unsafe { LAYOUT.v_to_p(image) } >> Meta::PAGE_BITS);
+        let file = range(5 * mega, 5 * mega + 2);
+        // 文件只有一个字，页中其余部分清零
+        let len = core::mem::size_of::<u64>();
+        space.map_lazy(file.clone(), ro, Backing::file(file.start, ppn, len));
+        for vpn in [base + 4 * mega + 3, file.start.val()] {
+            let addr = VPN::<Meta>::new(vpn).base().val();
+            assert!(space.resolve_fault(addr, Access::Read, true));
+        }
+        // 写只读区域、内核态执行用户内存都不处理
+        let addr = VPN::<Meta>::new(file.start.val() + 1).base().val();
+        assert!(!space.resolve_fault(addr, Access::Write, true));
+        assert!(!space.resolve_fault(addr, Access::Execute, false));
+        assert_eq!(space.minor_faults(), 2);
+        let (copy, _) = space.translate(file.start.base()).unwrap();
+        let copy = PageManager::<Meta>::p_to_v::<u64>(&Global, copy).as_ptr();
+        assert_eq!(unsafe { *copy }, unsafe { *(image as *const u64) });
+        assert_eq!(unsafe { *copy.add(1) }, 0);
+        let _eager = space.fork(Global, false);
+        let _cow = space.fork(Global, true);
+    }
+    let after = (frame::in_use(), free());
+    assert_eq!(before.0, after.0, "address space teardown leaked frames");
+    assert_eq!(before.1, after.1, "address space teardown leaked memory");
+    log::info!(
+        "address space teardown check passed, {} frames in use, {:#x} bytes free",
+        after.0,
+        after.1,
+    );
+}
+
+/// 写内核代码段和只读数据段的第一页和最后一页，检查都触发写缺页，再检查线性区的其他部分都不可执行。
+///
+/// `kernel` 是已经激活的内核地址空间，检查期间临时替换写缺页的处理函数。
+pub(crate) fn wx_check<Meta: VmMeta>(kernel: &AddressSpace<Meta, Global>) {
+    static FAULT: AtomicUsize = AtomicUsize::new(0);
+    fn record(ctx: &mut TrapContext) {
+        FAULT.store(ctx.stval, Ordering::Relaxed);
+        ctx.skip_instruction();
+    }
+    let info = unsafe { &LAYOUT };
+    trap::register(Cause::STORE_PAGE_FAULT, record);
+    for section in [info.text(), info.rodata()] {
+        for addr in [section.start, section.end - 1] {
+            FAULT.store(0, Ordering::Relaxed);
+            unsafe {
+                let ptr = addr as *mut u8;
+                ptr.write_volatile(ptr.read_volatile());
+            }
+            assert_eq!(FAULT.load(Ordering::Relaxed), addr, "{addr:#x} is writable");
+        }
+    }
+    trap::register(Cause::STORE_PAGE_FAULT, trap::page_fault);
+    if let Some(addr) = kernel.linear_executable() {
+        panic!("{addr:#x} in the linear map is executable");
+    }
+    log::info!("W^X check passed");
+}
+
+impl<Meta: VmMeta, M: PageManager<Meta>> AddressSpace<Meta, M> {
+    /// 包含虚地址 `addr` 的虚存区域。
+    pub fn segment_of(&self, addr: usize) -> Option<Range<usize>> {
+        self.segments
+            .get_key_value(&VAddr::<Meta>::new(addr).floor())
+            .map(|(seg, _)| seg.start.base().val()..seg.end.base().val())
+    }
+
+    /// 处理虚地址 `addr` 上的缺页，`user` 表示来自用户态。解决了返回 `true`。
+    ///
+    /// 只为用户内存处理缺页：区域必须有 `U` 和访问需要的权限，内核态不能执行用户内存。
+    pub fn resolve_fault(&mut self, addr: usize, access: Access, user: bool) -> bool {
+        let vpn = VAddr::<Meta>::new(addr).floor();
+        let vma = match self.segments.get(&vpn) {
+            Some(vma) => *vma,
+            None => return false,
+        };
+        let required = match access {
+            Access::Read => VmFlags::build_from_str("___U__R_"),
+            Access::Write => VmFlags::build_from_str("___U_W__"),
+            Access::Execute => VmFlags::build_from_str("___UX___"),
+        };
+        if !vma.flags.contains(required) || (!user && access == Access::Execute) {
+            return false;
+        }
+        let (_, _, pte) = self.walk(vpn, 0);
+        if !pte.is_valid() {
+            self.fault_in(vpn, vma);
+        } else if access == Access::Write && self.cow.contains(&vpn) {
+            self.copy_on_write(vpn);
+        } else {
+            return false;
+        }
+        self.minor_faults += 1;
+        true
+    }
+
+    /// 检查用户态能否访问 `range`，`write` 表示写访问。
+    ///
+    /// 范围必须在用户半区内，每页都要有 `U` 和 `R`，写访问还要有 `W`；
+    /// 按需映射和写时复制的页先按缺页处理。
+    pub fn check_user(&mut self, range: Range<usize>, write: bool) -> bool {
+        if range.start > range.end || range.end > unsafe { LAYOUT.offset() }.wrapping_neg() {
+            return false;
+        }
+        let required = if write {
+            VmFlags::build_from_str("___U_WR_")
+        } else {
+            VmFlags::build_from_str("___U__R_")
+        };
+        let mask = (1 << Meta::PAGE_BITS) - 1;
+        let mut pages = range.start >> Meta::PAGE_BITS..(range.end + mask) >> Meta::PAGE_BITS;
+        pages.all(|vpn| {
+            let addr = VPN::<Meta>::new(vpn).base();
+            let allowed = |space: &Self| {
+                matches!(space.translate(addr), Some((_, flags)) if flags.contains(required))
+            };
+            let access = if write { Access::Write } else { Access::Read };
+            allowed(self) || (self.resolve_fault(addr.val(), access, true) && allowed(self))
+        })
+    }
+}
+
+/// 陷入处理和使用者都通过锁访问地址空间，缺页发生在持有锁的代码中时不处理。
+impl<Meta: VmMeta, M: PageManager<Meta>> Segments for Mutex<AddressSpace<Meta, M>> {
+    fn segment_of(&self, addr: usize) -> Option<Range<usize>> {
+        self.try_lock()?.segment_of(addr)
+    }
+
+    fn resolve_fault(&self, addr: usize, access: Access, user: bool) -> bool {
+        self.try_lock()
+            .map_or(false, |mut space| space.resolve_fault(addr, access, user))
+    }
+
+    fn check_user(&self, range: Range<usize>, write: bool) -> bool {
+        self.try_lock()
+            .map_or(false, |mut space| space.check_user(range, write))
     }
 }
 
 impl<Meta: VmMeta, M: PageManager<Meta>> fmt::Debug for AddressSpace<Meta, M> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for seg in self.segments.iter() {
+        for (seg, vma) in self.segments.iter() {
             writeln!(
                 f,
-                "{:#x}..{:#x}",
+                "{:#x}..{:#x} {:?}",
                 seg.start.base().val(),
-                seg.end.base().val()
+                seg.end.base().val(),
+                vma.backing,
             )?;
         }
         writeln!(
@@ -0,0 +1,304 @@
+﻿//! 时钟和定时器。
+//!
+//! 单调时钟读取 `time` 寄存器，频率来自设备树 `/cpus/timebase-frequency`。
+//! 每个硬件线程有一个一次性或周期性的定时器，通过 SBI `set_timer` 编程，在时钟中断中调用回调。
+//! 回调运行在中断上下文中，不能分配内存，也不能等待被中断的代码。
+
+use crate::{
+    device_tree::{self, be_usize},
+    hart,
+    layout::KernelLayout,
+    param::Param,
+    trap::{self, Cause, TrapContext},
+};
+use core::{
+    ops::Add,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use riscv::register::{sie, sstatus, time};
+use spin::Once;
+
+param! {
+    /// 启动时是否检查定时器的精度。
+    static SELF_TEST: Param<bool> =
+        Param::new("timer-test", "启动时检查定时器、睡眠和忙等的精度，约 12 ms", false);
+}
+
+/// 设备树中没有时基频率时使用的值，与 QEMU virt 相同。
+const DEFAULT_FREQUENCY: u64 = 10_000_000;
+
+/// 时基频率（Hz）。
+static FREQUENCY: Once<u64> = Once::new();
+
+/// 每微秒的空循环次数。
+static LOOPS_PER_US: Once<u64> = Once::new();
+
+/// 单调时钟上的一个时刻，单位是时基的一拍。
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) struct Instant(u64);
+
+impl Instant {
+    /// 当前时刻。
+    #[inline]
+    pub fn now() -> Self {
+        Self(time::read() as _)
+    }
+
+    /// 时基的拍数。
+    #[inline]
+    pub fn ticks(self) -> u64 {
+        self.0
+    }
+
+    /// 从 `earlier` 到这个时刻经过的时间，`earlier` 更晚时为 0。
+    #[inline]
+    pub fn duration_since(self, earlier: Self) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    /// 从这个时刻到现在经过的时间。
+    #[inline]
+    pub fn elapsed(self) -> Duration {
+        Self::now().duration_since(self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Self;
+
+    #[inline]
+    fn add(self, rhs: Duration) -> Self {
+        Self(self.0.saturating_add(duration_to_ticks(rhs)))
+    }
+}
+
+/// 时基频率（Hz）。
+#[inline]
+pub(crate) fn frequency() -> u64 {
+    *FREQUENCY.get().expect("timer is not initialized")
+}
+
+/// 把时基的拍数换算成时间。
+pub(crate) fn ticks_to_duration(ticks: u64) -> Duration {
+    let freq = frequency();
+    let nanos = (ticks % freq) as u128 * 1_000_000_000 / freq as u128;
+    Duration::new(ticks / freq, nanos as _)
+}
+
+/// 把时间换算成时基的拍数，不足一拍的部分舍去。
+pub(crate) fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = duration.as_nanos() * frequency() as u128 / 1_000_000_000;
+    ticks.min(u64::MAX as u128) as _
+}
+
+/// 一个硬件线程的定时器。
+#[derive(Clone, Copy)]
+struct Timer {
+    /// 下次调用回调的时刻，没有定时器时为 `u64::MAX`。
+    deadline: u64,
+    /// 周期，0 表示一次性定时器。
+    period: u64,
+    callback: Option<fn()>,
+    /// [`sleep`] 等待的时刻，没有等待时为 `u64::MAX`。
+    wake: u64,
+}
+
+impl Timer {
+    const IDLE: Self = Self {
+        deadline: u64::MAX,
+        period: 0,
+        callback: None,
+        wake: u64::MAX,
+    };
+
+    /// 按最近的时刻编程下一次时钟中断。
+    #[inline]
+    fn program(&self) {
+        sbi_rt::set_timer(self.deadline.min(self.wake));
+    }
+}
+
+/// 按硬件线程编号排列的定时器。
+static mut TIMERS: [Timer; KernelLayout::MAX_HARTS] = [Timer::IDLE; KernelLayout::MAX_HARTS];
+
+/// 关闭时钟中断，修改当前硬件线程的定时器并重新编程。
+fn with_timer(f: impl FnOnce(&mut Timer)) {
+    unsafe {
+        sie::clear_stimer();
+        let timer = &mut TIMERS[hart::id()];
+        f(timer);
+        timer.program();
+        sie::set_stimer();
+    }
+}
+
+/// 读取时基频率，校准空循环，注册时钟中断处理函数，然后为当前硬件线程打开时钟中断。
+///
+/// 必须在设备树重定位之后、启动副核之前调用。
+pub(crate) fn init() {
+    use dtb_walker::{DtbObj, Property, WalkOperation::*};
+    let mut freq = None;
+    device_tree::get().dtb().walk(|path, obj| match obj {
+        DtbObj::SubNode { name } => {
+            if path.is_root() && name.starts_with("cpus") {
+                StepInto
+            } else {
+                StepOver
+            }
+        }
+        DtbObj::Property(Property::General { name, value })
+            if path.name().starts_with("cpus") && name.starts_with("timebase-frequency") =>
+        {
+            freq = Some(be_usize(value) as u64);
+            StepOut
+        }
+        DtbObj::Property(_) => StepOver,
+    });
+    let freq = match freq {
+        Some(freq) if freq > 0 => freq,
+        _ => {
+            log::warn!("timebase-frequency not found, assume {DEFAULT_FREQUENCY} Hz");
+            DEFAULT_FREQUENCY
+        }
+    };
+    FREQUENCY.call_once(|| freq);
+    let loops = *LOOPS_PER_US.call_once(calibrate);
+    log::info!("timebase: {freq} Hz, {loops} loops/us");
+    trap::register(Cause::SUPERVISOR_TIMER, interrupt);
+    init_hart();
+    if SELF_TEST.get() {
+        self_test();
+    }
+}
+
+/// 为当前硬件线程打开时钟中断。
+pub(crate) fn init_hart() {
+    unsafe {
+        TIMERS[hart::id()] = Timer::IDLE;
+        TIMERS[hart::id()].program();
+        sie::set_stimer();
+        sstatus::set_sie();
+    }
+}
+
+/// 在 `deadline` 调用一次 `callback`，替换当前硬件线程已有的定时器。
+pub(crate) fn set_oneshot(deadline: Instant, callback: fn()) {
+    with_timer(|timer| {
+        timer.deadline = deadline.0;
+        timer.period = 0;
+        timer.callback = Some(callback);
+    });
+}
+
+/// 每隔 `period` 调用一次 `callback`，替换当前硬件线程已有的定时器。
+pub(crate) fn set_periodic(period: Duration, callback: fn()) {
+    let ticks = duration_to_ticks(period).max(1);
+    with_timer(|timer| {
+        timer.deadline = Instant::now().0.saturating_add(ticks);
+        timer.period = ticks;
+        timer.callback = Some(callback);
+    });
+}
+
+/// 取消当前硬件线程的定时器。
+pub(crate) fn cancel() {
+    with_timer(|timer| {
+        timer.deadline = u64::MAX;
+        timer.period = 0;
+        timer.callback = None;
+    });
+}
+
+/// 睡眠至少 `duration`，期间硬件线程停在 `wfi`。
+///
+/// 不影响当前硬件线程的定时器。
+pub(crate) fn sleep(duration: Duration) {
+    let wake = Instant::now() + duration;
+    with_timer(|timer| timer.wake = timer.wake.min(wake.0));
+    while Instant::now() < wake {
+        unsafe { riscv::asm::wfi() };
+    }
+}
+
+/// 忙等至少 `duration`，用于时基精度不够的短延时。
+pub(crate) fn delay(duration: Duration) {
+    let loops = duration.as_nanos() * *LOOPS_PER_US.get().unwrap() as u128 / 1000;
+    spin(loops as _);
+}
+
+/// 空循环 `loops` 次。
+#[inline(never)]
+fn spin(loops: u64) {
+    for _ in 0..loops {
+        unsafe { core::arch::asm!("nop") };
+    }
+}
+
+/// 测量每微秒的空循环次数。
+fn calibrate() -> u64 {
+    const LOOPS: u64 = 1 << 16;
+    // 先预热一次
+    spin(LOOPS);
+    let start = Instant::now();
+    spin(LOOPS);
+    let nanos = start.elapsed().as_nanos().max(1);
+    (LOOPS as u128 * 1000 / nanos).max(1) as _
+}
+
+/// 时钟中断。
+fn interrupt(_ctx: &mut TrapContext) {
+    let timer = unsafe { &mut TIMERS[hart::id()] };
+    let now = Instant::now().0;
+    if timer.wake <= now {
+        timer.wake = u64::MAX;
+    }
+    let callback = if timer.deadline <= now {
+        let callback = timer.callback;
+        if timer.period == 0 {
+            *timer = Timer {
+                wake: timer.wake,
+                ..Timer::IDLE
+            };
+        } else {
+            // 错过的周期不补
+            timer.deadline = timer.deadline.saturating_add(timer.period).max(now + 1);
+        }
+        callback
+    } else {
+        None
+    };
+    timer.program();
+    if let Some(callback) = callback {
+        callback();
+    }
+}
+
+bench!(instant_now, |b| b.iter(Instant::now));
+
+/// 检查定时器、睡眠和忙等的精度。
+fn self_test() {
+    static FIRED: AtomicUsize = AtomicUsize::new(0);
+    fn tick() {
+        FIRED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let start = Instant::now();
+    set_oneshot(start + Duration::from_millis(1), tick);
+    sleep(Duration::from_millis(2));
+    let oneshot = FIRED.swap(0, Ordering::Relaxed);
+    set_periodic(Duration::from_millis(1), tick);
+    sleep(Duration::from_millis(10));
+    cancel();
+    let periodic = FIRED.load(Ordering::Relaxed);
+    let slept = start.elapsed();
+    let start = Instant::now();
+    delay(Duration::from_micros(100));
+    let delayed = start.elapsed();
+    log::debug!(
+        "timer self test: oneshot fired {oneshot}, periodic fired {periodic} in 10ms, slept {slept:?}, delay(100us) took {delayed:?}"
+    );
+    if oneshot != 1 || periodic == 0 {
+        log::warn!("timer interrupts are not delivered as expected");
+    }
+}
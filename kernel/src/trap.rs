@@ -0,0 +1,651 @@
+﻿//! 陷入处理。
+//!
+//! 陷入入口保存全部通用寄存器和陷入相关的控制状态寄存器，然后按 `scause` 分发到注册的处理函数。
+//! 用户态的 `ecall` 再按 `a7` 分发到注册的系统调用。
+//! 没有处理函数的陷入打印一份报告后进入 panic。
+//!
+//! 控制台可用之前使用 [`early_handler`]，它只打印陷入寄存器然后关机。
+
+use crate::{hart, layout::KernelLayout};
+use core::{marker::PhantomData, ops::Range, ptr::NonNull};
+use riscv::register::{
+    scause, sepc, stval,
+    stvec::{self, TrapMode},
+};
+use sbi_rt::{legacy, system_reset, RESET_REASON_SYSTEM_FAILURE, RESET_TYPE_SHUTDOWN};
+
+/// 陷入时保存的上下文。
+#[repr(C)]
+pub(crate) struct TrapContext {
+    /// 通用寄存器，`x[0]` 不使用。
+    pub x: [usize; 32],
+    pub sstatus: usize,
+    pub sepc: usize,
+    pub scause: usize,
+    pub stval: usize,
+}
+
+impl TrapContext {
+    /// 陷入原因。
+    #[inline]
+    pub fn cause(&self) -> Cause {
+        Cause::from_bits(self.scause)
+    }
+
+    /// 陷入是否来自用户态（`sstatus.SPP` 为 0）。
+    #[inline]
+    pub fn from_user(&self) -> bool {
+        self.sstatus & (1 << 8) == 0
+    }
+
+    /// 跳过 `sepc` 处的指令，可能是压缩指令。
+    #[inline]
+    pub fn skip_instruction(&mut self) {
+        let low = unsafe { (self.sepc as *const u16).read_volatile() };
+        self.sepc += if low & 0b11 == 0b11 { 4 } else { 2 };
+    }
+}
+
+/// 陷入原因。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Cause {
+    /// 中断，包含中断号。
+    Interrupt(usize),
+    /// 异常，包含异常号。
+    Exception(usize),
+}
+
+impl Cause {
+    pub const SUPERVISOR_SOFT: Self = Self::Interrupt(1);
+    pub const SUPERVISOR_TIMER: Self = Self::Interrupt(5);
+    pub const BREAKPOINT: Self = Self::Exception(3);
+    pub const USER_ECALL: Self = Self::Exception(8);
+    pub const INSTRUCTION_PAGE_FAULT: Self = Self::Exception(12);
+    pub const LOAD_PAGE_FAULT: Self = Self::Exception(13);
+    pub const STORE_PAGE_FAULT: Self = Self::Exception(15);
+
+    /// 最高位是中断标志。
+    #[inline]
+    pub const fn from_bits(scause: usize) -> Self {
+        const INTERRUPT: usize = 1 << (usize::BITS - 1);
+        if scause & INTERRUPT != 0 {
+            Self::Interrupt(scause & !INTERRUPT)
+        } else {
+            Self::Exception(scause)
+        }
+    }
+
+    /// 在处理函数表中的序号。
+    #[inline]
+    const fn index(self) -> Option<usize> {
+        match self {
+            Self::Exception(code) if code < 16 => Some(code),
+            Self::Interrupt(code) if code < 16 => Some(16 + code),
+            _ => None,
+        }
+    }
+
+    /// 特权级手册中的名字。
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Interrupt(1) => "supervisor software interrupt",
+            Self::Interrupt(5) => "supervisor timer interrupt",
+            Self::Interrupt(9) => "supervisor external interrupt",
+            Self::Interrupt(_) => "unknown interrupt",
+            Self::Exception(0) => "instruction address misaligned",
+            Self::Exception(1) => "instruction access fault",
+            Self::Exception(2) => "illegal instruction",
+            Self::Exception(3) => "breakpoint",
+            Self::Exception(4) => "load address misaligned",
+            Self::Exception(5) => "load access fault",
+            Self::Exception(6) => "store/AMO address misaligned",
+            Self::Exception(7) => "store/AMO access fault",
+            Self::Exception(8) => "environment call from U-mode",
+            Self::Exception(9) => "environment call from S-mode",
+            Self::Exception(12) => "instruction page fault",
+            Self::Exception(13) => "load page fault",
+            Self::Exception(15) => "store/AMO page fault",
+            Self::Exception(_) => "unknown exception",
+        }
+    }
+}
+
+/// 陷入处理函数。
+pub(crate) type Handler = fn(&mut TrapContext);
+
+/// 系统调用处理函数，参数是 `a0`~`a5`，返回值写回 `a0`。
+pub(crate) type Syscall = fn(&mut TrapContext, [usize; 6]) -> isize;
+
+/// 按 [`Cause::index`] 排列的处理函数。
+static mut HANDLERS: [Option<Handler>; 32] = [None; 32];
+
+/// 最大的系统调用号。
+const MAX_SYSCALL: usize = 512;
+
+/// 按系统调用号排列的处理函数。
+static mut SYSCALLS: [Option<Syscall>; MAX_SYSCALL] = [None; MAX_SYSCALL];
+
+/// 未实现的系统调用的返回值。
+const ENOSYS: isize = -38;
+
+/// 引起缺页的访问类型。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// 陷入处理看到的地址空间，用于报告和缺页处理。
+///
+/// 陷入可能打断正在使用地址空间的代码，实现者自己加锁，锁被占用时不处理。
+pub(crate) trait Segments {
+    /// 包含虚地址 `addr` 的段。
+    fn segment_of(&self, addr: usize) -> Option<Range<usize>>;
+
+    /// 处理虚地址 `addr` 上的缺页，`user` 表示来自用户态。解决了返回 `true`。
+    fn resolve_fault(&self, addr: usize, access: Access, user: bool) -> bool;
+
+    /// 检查用户态能否访问 `range`，`write` 表示写访问。
+    fn check_user(&self, range: Range<usize>, write: bool) -> bool;
+}
+
+/// 陷入处理默认使用的地址空间，由 [`set_space`] 在启动副核之前设置。
+static mut KERNEL_SPACE: Option<&'static dyn Segments> = None;
+
+/// 每个硬件线程在 [`with_space`] 期间使用的地址空间，只由本硬件线程读写。
+static mut SPACES: [Option<NonNull<dyn Segments>>; KernelLayout::MAX_HARTS] =
+    [None; KernelLayout::MAX_HARTS];
+
+/// 为当前硬件线程设置陷入入口。
+///
+/// 必须在跃迁到高地址并初始化控制台之后调用。
+pub(crate) fn init() {
+    unsafe {
+        core::arch::asm!("csrw sscratch, zero");
+        stvec::write(trap_entry as usize, TrapMode::Direct);
+    }
+}
+
+/// 注册 `cause` 的处理函数，替换已有的。
+///
+/// 处理函数表不加锁，只能在启动副核之前调用。
+pub(crate) fn register(cause: Cause, handler: Handler) {
+    match cause.index() {
+        Some(i) => unsafe { HANDLERS[i] = Some(handler) },
+        None => panic!("cannot register handler for {cause:?}"),
+    }
+}
+
+/// 注册系统调用 `id` 的处理函数，替换已有的。
+///
+/// 与 [`register`] 一样只能在启动副核之前调用。
+pub(crate) fn register_syscall(id: usize, handler: Syscall) {
+    assert!(id < MAX_SYSCALL, "syscall id {id} out of range");
+    unsafe { SYSCALLS[id] = Some(handler) };
+}
+
+/// 设置陷入处理默认使用的地址空间。
+///
+/// 与 [`register`] 一样只能在启动副核之前调用。
+pub(crate) fn set_space(space: &'static dyn Segments) {
+    unsafe { KERNEL_SPACE = Some(space) };
+}
+
+/// 在 `f` 运行期间让当前硬件线程的陷入处理使用 `space`，之后恢复之前的地址空间。
+pub(crate) fn with_space<S: Segments + 'static, T>(space: &S, f: impl FnOnce() -> T) -> T {
+    let _guard = SpaceGuard::new(space);
+    f()
+}
+
+/// 在作用域内替换当前硬件线程的地址空间，离开作用域时恢复。
+///
+/// 槽里的指针不比借用活得久，陷入处理只在守卫存在期间解引用它。
+struct SpaceGuard<'a> {
+    hartid: usize,
+    previous: Option<NonNull<dyn Segments>>,
+    _space: PhantomData<&'a dyn Segments>,
+}
+
+impl<'a> SpaceGuard<'a> {
+    fn new(space: &'a (dyn Segments + 'static)) -> Self {
+        let hartid = hart::id();
+        let previous = unsafe { SPACES[hartid].replace(NonNull::from(space)) };
+        Self {
+            hartid,
+            previous,
+            _space: PhantomData,
+        }
+    }
+}
+
+impl Drop for SpaceGuard<'_> {
+    fn drop(&mut self) {
+        unsafe { SPACES[self.hartid] = self.previous };
+    }
+}
+
+/// 当前硬件线程的陷入处理使用的地址空间。
+fn with_current<T>(f: impl FnOnce(&dyn Segments) -> T) -> Option<T> {
+    match unsafe { SPACES[hart::id()] } {
+        // 槽非空说明对应的 `SpaceGuard` 还在，借用有效
+        Some(space) => Some(f(unsafe { space.as_ref() })),
+        None => unsafe { KERNEL_SPACE }.map(f),
+    }
+}
+
+/// 内核态陷入。
+extern "C" fn trap_handler(ctx: &mut TrapContext) {
+    dispatch(ctx)
+}
+
+/// 陷入分发。
+pub(crate) fn dispatch(ctx: &mut TrapContext) {
+    let cause = ctx.cause();
+    if cause == Cause::USER_ECALL {
+        syscall(ctx);
+    } else if let Some(handler) = cause.index().and_then(|i| unsafe { HANDLERS[i] }) {
+        handler(ctx);
+    } else {
+        report(ctx);
+        panic!("unhandled trap: {}", cause.name());
+    }
+}
+
+/// 按 `a7` 分发系统调用，然后跳过 `ecall`。
+pub(crate) fn syscall(ctx: &mut TrapContext) {
+    let id = ctx.x[17];
+    let args = ctx.x[10..16].try_into().unwrap();
+    let ret = match unsafe { SYSCALLS.get(id).copied().flatten() } {
+        Some(handler) => handler(ctx, args),
+        None => {
+            log::warn!("unsupported syscall {id} at {:#x}", ctx.sepc);
+            ENOSYS
+        }
+    };
+    ctx.x[10] = ret as _;
+    ctx.sepc += 4;
+}
+
+/// 断点：打印位置后继续执行。
+pub(crate) fn breakpoint(ctx: &mut TrapContext) {
+    log::warn!("breakpoint at {:#x}", ctx.sepc);
+    ctx.skip_instruction();
+}
+
+/// 缺页：交给当前地址空间处理，处理不了时报告并停机。
+pub(crate) fn page_fault(ctx: &mut TrapContext) {
+    if !resolve_fault(ctx) {
+        report(ctx);
+        panic!("unhandled {} at {:#x}", ctx.cause().name(), ctx.stval);
+    }
+}
+
+/// 把缺页交给当前地址空间处理，不是缺页或没有解决时返回 `false`。
+pub(crate) fn resolve_fault(ctx: &TrapContext) -> bool {
+    let access = match ctx.cause() {
+        Cause::LOAD_PAGE_FAULT => Access::Read,
+        Cause::STORE_PAGE_FAULT => Access::Write,
+        Cause::INSTRUCTION_PAGE_FAULT => Access::Execute,
+        _ => return false,
+    };
+    with_current(|space| space.resolve_fault(ctx.stval, access, ctx.from_user())).unwrap_or(false)
+}
+
+/// 检查当前地址空间中用户态能否访问 `range`，系统调用访问用户内存之前调用。
+pub(crate) fn check_user(range: Range<usize>, write: bool) -> bool {
+    with_current(|space| space.check_user(range, write)).unwrap_or(false)
+}
+
+/// 打印陷入报告。
+pub(crate) fn report(ctx: &TrapContext) {
+    let cause = ctx.cause();
+    println!(
+        "
+trap: {} ({cause:?}) from {}
+sepc  = {:#018x}
+stval = {:#018x}
+ra    = {:#018x}
+sp    = {:#018x}",
+        cause.name(),
+        if ctx.from_user() { "U-mode" } else { "S-mode" },
+        ctx.sepc,
+        ctx.stval,
+        ctx.x[1],
+        ctx.x[2],
+    );
+    match with_current(|space| space.segment_of(ctx.stval)) {
+        Some(Some(seg)) => println!("stval in segment {:#x}..{:#x}", seg.start, seg.end),
+        Some(None) => println!("stval not in any segment"),
+        None => println!("no address space"),
+    }
+}
+
+/// 早期陷入处理使用的栈，所有硬件线程共用，只用于打印后关机。
+static mut EARLY_STACK: [usize; 512] = [0; 512];
+
+/// 启动早期的陷入处理，在 `_start` 中以物理地址设置。
+///
+/// 陷入时的栈不可信，先换到 [`EARLY_STACK`] 再进入 [`early_trap`]。
+#[naked]
+#[repr(align(4))]
+pub(crate) unsafe extern "C" fn early_handler() -> ! {
+    core::arch::asm!(
+        "   lla  sp, {stack}",
+        "   li   t0, {size}",
+        "   add  sp, sp, t0",
+        "   andi sp, sp, -16",
+        "   j    {trap}",
+        stack = sym EARLY_STACK,
+        size  = const core::mem::size_of::<[usize; 512]>(),
+        trap  =   sym early_trap,
+        options(noreturn),
+    )
+}
+
+/// 早期陷入处理的 Rust 部分。
+///
+/// 可能在重定位之前运行，所以不使用格式化、堆和控制台单例，直接通过 SBI 打印 `scause`、`sepc`、`stval` 后关机。
+extern "C" fn early_trap() -> ! {
+    early_print(b"\nearly trap: scause = ");
+    early_hex(scause::read().bits());
+    early_print(b", sepc = ");
+    early_hex(sepc::read());
+    early_print(b", stval = ");
+    early_hex(stval::read());
+    early_print(b"\n");
+    system_reset(RESET_TYPE_SHUTDOWN, RESET_REASON_SYSTEM_FAILURE);
+    // 不能用 unreachable!()，格式化可能需要重定位
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+fn early_print(s: &[u8]) {
+    for c in s {
+        #[allow(deprecated)]
+        legacy::console_putchar(*c as _);
+    }
+}
+
+fn early_hex(n: usize) {
+    early_print(b"0x");
+    for i in (0..usize::BITS / 4).rev() {
+        let digit = ((n >> (i * 4)) & 0xf) as u8;
+        early_print(&[if digit < 10 {
+            b'0' + digit
+        } else {
+            b'a' + digit - 10
+        }]);
+    }
+}
+
+/// 上下文的字节数。
+const CONTEXT_SIZE: usize = core::mem::size_of::<TrapContext>();
+
+/// 用户态的上下文。
+///
+/// 运行在用户态时 `sscratch` 指向它，用户态陷入时寄存器保存到这里，然后回到 [`execute`](Self::execute) 的调用者。
+#[repr(C)]
+pub(crate) struct UserContext {
+    pub ctx: TrapContext,
+    /// 进入用户态前的内核栈指针。
+    kernel_sp: usize,
+}
+
+impl UserContext {
+    /// 从 `entry` 开始执行、栈指针为 `sp` 的用户态上下文。
+    pub fn new(entry: usize, sp: usize) -> Self {
+        const SIE: usize = 1 << 1;
+        const SPIE: usize = 1 << 5;
+        const SPP: usize = 1 << 8;
+        let sstatus: usize;
+        unsafe { core::arch::asm!("csrr {}, sstatus", out(reg) sstatus) };
+        let mut ctx = TrapContext {
+            x: [0; 32],
+            sstatus: (sstatus & !SPP & !SIE) | SPIE,
+            sepc: entry,
+            scause: 0,
+            stval: 0,
+        };
+        ctx.x[2] = sp;
+        Self { ctx, kernel_sp: 0 }
+    }
+
+    /// 切换到用户态执行，直到下一次陷入。
+    ///
+    /// 返回时陷入的信息在 [`ctx`](Self::ctx) 中，由调用者处理。
+    #[inline]
+    pub fn execute(&mut self) {
+        unsafe { enter_user(self) }
+    }
+}
+
+/// 保存内核的被调用者保存寄存器和 `gp`、`tp`，恢复用户上下文并 `sret`。
+///
+/// 用户态陷入时 [`trap_entry`] 把上下文存回 `ctx`，然后从这里返回。
+#[naked]
+unsafe extern "C" fn enter_user(ctx: &mut UserContext) {
+    core::arch::asm!(
+        "   addi sp, sp, -16*8",
+        "   sd   ra,  0*8(sp)",
+        "   sd   s0,  1*8(sp)",
+        "   sd   s1,  2*8(sp)",
+        "   sd   s2,  3*8(sp)",
+        "   sd   s3,  4*8(sp)",
+        "   sd   s4,  5*8(sp)",
+        "   sd   s5,  6*8(sp)",
+        "   sd   s6,  7*8(sp)",
+        "   sd   s7,  8*8(sp)",
+        "   sd   s8,  9*8(sp)",
+        "   sd   s9, 10*8(sp)",
+        "   sd   s10,11*8(sp)",
+        "   sd   s11,12*8(sp)",
+        "   csrr t0, sstatus",
+        "   sd   t0, 13*8(sp)",
+        "   sd   gp, 14*8(sp)",
+        "   sd   tp, 15*8(sp)",
+        // 切换期间不能响应中断
+        "   csrci sstatus, 2",
+        "   sd   sp, {size}(a0)",
+        "   csrw sscratch, a0",
+        "   ld   t0, 32*8(a0)",
+        "   csrw sstatus, t0",
+        "   ld   t0, 33*8(a0)",
+        "   csrw sepc, t0",
+        "   ld   x1,   1*8(a0)",
+        "   ld   x2,   2*8(a0)",
+        "   ld   x3,   3*8(a0)",
+        "   ld   x4,   4*8(a0)",
+        "   ld   x5,   5*8(a0)",
+        "   ld   x6,   6*8(a0)",
+        "   ld   x7,   7*8(a0)",
+        "   ld   x8,   8*8(a0)",
+        "   ld   x9,   9*8(a0)",
+        "   ld   x11, 11*8(a0)",
+        "   ld   x12, 12*8(a0)",
+        "   ld   x13, 13*8(a0)",
+        "   ld   x14, 14*8(a0)",
+        "   ld   x15, 15*8(a0)",
+        "   ld   x16, 16*8(a0)",
+        "   ld   x17, 17*8(a0)",
+        "   ld   x18, 18*8(a0)",
+        "   ld   x19, 19*8(a0)",
+        "   ld   x20, 20*8(a0)",
+        "   ld   x21, 21*8(a0)",
+        "   ld   x22, 22*8(a0)",
+        "   ld   x23, 23*8(a0)",
+        "   ld   x24, 24*8(a0)",
+        "   ld   x25, 25*8(a0)",
+        "   ld   x26, 26*8(a0)",
+        "   ld   x27, 27*8(a0)",
+        "   ld   x28, 28*8(a0)",
+        "   ld   x29, 29*8(a0)",
+        "   ld   x30, 30*8(a0)",
+        "   ld   x31, 31*8(a0)",
+        "   ld   x10, 10*8(a0)",
+        "   sret",
+        size = const CONTEXT_SIZE,
+        options(noreturn),
+    )
+}
+
+/// 陷入入口。
+///
+/// 内核态陷入时 `sscratch` 为 0，在当前栈上保存上下文并处理；
+/// 用户态陷入时 `sscratch` 指向 [`UserContext`]，保存上下文后回到内核栈，从 [`enter_user`] 返回。
+#[naked]
+#[repr(align(4))]
+unsafe extern "C" fn trap_entry() -> ! {
+    core::arch::asm!(
+        "   csrrw sp, sscratch, sp",
+        "   bnez sp, 1f",
+        "   csrrw sp, sscratch, sp",
+        "   addi sp, sp, -{size}",
+        "   sd   x1,   1*8(sp)",
+        "   sd   x3,   3*8(sp)",
+        "   sd   x4,   4*8(sp)",
+        "   sd   x5,   5*8(sp)",
+        "   sd   x6,   6*8(sp)",
+        "   sd   x7,   7*8(sp)",
+        "   sd   x8,   8*8(sp)",
+        "   sd   x9,   9*8(sp)",
+        "   sd   x10, 10*8(sp)",
+        "   sd   x11, 11*8(sp)",
+        "   sd   x12, 12*8(sp)",
+        "   sd   x13, 13*8(sp)",
+        "   sd   x14, 14*8(sp)",
+        "   sd   x15, 15*8(sp)",
+        "   sd   x16, 16*8(sp)",
+        "   sd   x17, 17*8(sp)",
+        "   sd   x18, 18*8(sp)",
+        "   sd   x19, 19*8(sp)",
+        "   sd   x20, 20*8(sp)",
+        "   sd   x21, 21*8(sp)",
+        "   sd   x22, 22*8(sp)",
+        "   sd   x23, 23*8(sp)",
+        "   sd   x24, 24*8(sp)",
+        "   sd   x25, 25*8(sp)",
+        "   sd   x26, 26*8(sp)",
+        "   sd   x27, 27*8(sp)",
+        "   sd   x28, 28*8(sp)",
+        "   sd   x29, 29*8(sp)",
+        "   sd   x30, 30*8(sp)",
+        "   sd   x31, 31*8(sp)",
+        "   addi t0, sp, {size}",
+        "   sd   t0,   2*8(sp)",
+        "   csrr t0, sstatus",
+        "   sd   t0,  32*8(sp)",
+        "   csrr t0, sepc",
+        "   sd   t0,  33*8(sp)",
+        "   csrr t0, scause",
+        "   sd   t0,  34*8(sp)",
+        "   csrr t0, stval",
+        "   sd   t0,  35*8(sp)",
+        "   mv   a0, sp",
+        "   call {handler}",
+        "   ld   t0,  32*8(sp)",
+        "   csrw sstatus, t0",
+        "   ld   t0,  33*8(sp)",
+        "   csrw sepc, t0",
+        "   ld   x1,   1*8(sp)",
+        "   ld   x3,   3*8(sp)",
+        "   ld   x4,   4*8(sp)",
+        "   ld   x5,   5*8(sp)",
+        "   ld   x6,   6*8(sp)",
+        "   ld   x7,   7*8(sp)",
+        "   ld   x8,   8*8(sp)",
+        "   ld   x9,   9*8(sp)",
+        "   ld   x10, 10*8(sp)",
+        "   ld   x11, 11*8(sp)",
+        "   ld   x12, 12*8(sp)",
+        "   ld   x13, 13*8(sp)",
+        "   ld   x14, 14*8(sp)",
+        "   ld   x15, 15*8(sp)",
+        "   ld   x16, 16*8(sp)",
+        "   ld   x17, 17*8(sp)",
+        "   ld   x18, 18*8(sp)",
+        "   ld   x19, 19*8(sp)",
+        "   ld   x20, 20*8(sp)",
+        "   ld   x21, 21*8(sp)",
+        "   ld   x22, 22*8(sp)",
+        "   ld   x23, 23*8(sp)",
+        "   ld   x24, 24*8(sp)",
+        "   ld   x25, 25*8(sp)",
+        "   ld   x26, 26*8(sp)",
+        "   ld   x27, 27*8(sp)",
+        "   ld   x28, 28*8(sp)",
+        "   ld   x29, 29*8(sp)",
+        "   ld   x30, 30*8(sp)",
+        "   ld   x31, 31*8(sp)",
+        "   addi sp, sp, {size}",
+        "   sret",
+        // 用户态陷入，sp 指向用户上下文，sscratch 是用户栈指针
+        "1:",
+        "   sd   x1,   1*8(sp)",
+        "   sd   x3,   3*8(sp)",
+        "   sd   x4,   4*8(sp)",
+        "   sd   x5,   5*8(sp)",
+        "   sd   x6,   6*8(sp)",
+        "   sd   x7,   7*8(sp)",
+        "   sd   x8,   8*8(sp)",
+        "   sd   x9,   9*8(sp)",
+        "   sd   x10, 10*8(sp)",
+        "   sd   x11, 11*8(sp)",
+        "   sd   x12, 12*8(sp)",
+        "   sd   x13, 13*8(sp)",
+        "   sd   x14, 14*8(sp)",
+        "   sd   x15, 15*8(sp)",
+        "   sd   x16, 16*8(sp)",
+        "   sd   x17, 17*8(sp)",
+        "   sd   x18, 18*8(sp)",
+        "   sd   x19, 19*8(sp)",
+        "   sd   x20, 20*8(sp)",
+        "   sd   x21, 21*8(sp)",
+        "   sd   x22, 22*8(sp)",
+        "   sd   x23, 23*8(sp)",
+        "   sd   x24, 24*8(sp)",
+        "   sd   x25, 25*8(sp)",
+        "   sd   x26, 26*8(sp)",
+        "   sd   x27, 27*8(sp)",
+        "   sd   x28, 28*8(sp)",
+        "   sd   x29, 29*8(sp)",
+        "   sd   x30, 30*8(sp)",
+        "   sd   x31, 31*8(sp)",
+        "   csrr t0, sscratch",
+        "   sd   t0,   2*8(sp)",
+        "   csrw sscratch, zero",
+        "   csrr t0, sstatus",
+        "   sd   t0,  32*8(sp)",
+        "   csrr t0, sepc",
+        "   sd   t0,  33*8(sp)",
+        "   csrr t0, scause",
+        "   sd   t0,  34*8(sp)",
+        "   csrr t0, stval",
+        "   sd   t0,  35*8(sp)",
+        // 回到内核栈，恢复 enter_user 保存的寄存器并返回
+        "   ld   sp, {size}(sp)",
+        "   ld   ra,  0*8(sp)",
+        "   ld   s0,  1*8(sp)",
+        "   ld   s1,  2*8(sp)",
+        "   ld   s2,  3*8(sp)",
+        "   ld   s3,  4*8(sp)",
+        "   ld   s4,  5*8(sp)",
+        "   ld   s5,  6*8(sp)",
+        "   ld   s6,  7*8(sp)",
+        "   ld   s7,  8*8(sp)",
+        "   ld   s8,  9*8(sp)",
+        "   ld   s9, 10*8(sp)",
+        "   ld   s10,11*8(sp)",
+        "   ld   s11,12*8(sp)",
+        "   ld   t0, 13*8(sp)",
+        "   csrw sstatus, t0",
+        "   ld   gp, 14*8(sp)",
+        "   ld   tp, 15*8(sp)",
+        "   addi sp, sp, 16*8",
+        "   ret",
+        size    = const CONTEXT_SIZE,
+        handler =   sym trap_handler,
+        options(noreturn),
+    )
+}
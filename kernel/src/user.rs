@@ -0,0 +1,164 @@
+﻿//! 用户态程序。
+//!
+//! 用户程序运行在共用内核映射的用户地址空间中，通过 `ecall` 请求系统调用，`a7` 是调用号，`a0`~`a5` 是参数。
+//! 目前只有一个用户程序，[`run`] 在当前硬件线程上运行它直到退出。
+
+use crate::{
+    boot::PagingMode,
+    space::AddressSpace,
+    trap::{self, Cause, TrapContext, UserContext},
+    Global, LAYOUT,
+};
+use riscv::register::satp;
+use spin::Mutex;
+
+/// 向文件描述符写入：`(fd, buf, len)`，返回写入的字节数。
+const WRITE: usize = 64;
+/// 退出：`(code)`，不返回。
+const EXIT: usize = 93;
+/// 让出处理器，返回 0。
+const YIELD: usize = 124;
+/// 读取计数器，`a0` 返回 `cycle`，`a1` 返回 `instret`。
+const COUNTERS: usize = 500;
+
+/// 参数错误。
+const EINVAL: isize = -22;
+/// 地址错误。
+const EFAULT: isize = -14;
+/// 文件描述符错误。
+const EBADF: isize = -9;
+
+/// 注册系统调用，允许用户态直接读 `cycle`、`time` 和 `instret`。
+pub(crate) fn init() {
+    trap::register_syscall(WRITE, write);
+    trap::register_syscall(YIELD, yield_);
+    trap::register_syscall(COUNTERS, counters);
+    unsafe { core::arch::asm!("csrs scounteren, {}", in(reg) 0b111) };
+}
+
+/// 在用户地址空间 `space` 中运行 `ctx`，直到它调用 `exit`，返回退出码。
+///
+/// 无法处理的陷入打印报告后结束程序，返回 -1。
+pub(crate) fn run<Meta: PagingMode>(
+    space: &Mutex<AddressSpace<Meta, Global>>,
+    ctx: &mut UserContext,
+) -> isize {
+    let saved = satp::read().bits();
+    space.lock().activate();
+    let code = trap::with_space(space, || loop {
+        ctx.execute();
+        let ctx = &mut ctx.ctx;
+        match ctx.cause() {
+            Cause::USER_ECALL if ctx.x[17] == EXIT => break ctx.x[10] as isize,
+            Cause::USER_ECALL => trap::syscall(ctx),
+            Cause::Interrupt(_) => trap::dispatch(ctx),
+            _ if trap::resolve_fault(ctx) => {}
+            cause => {
+                trap::report(ctx);
+                log::warn!("user program killed by {}", cause.name());
+                break -1;
+            }
+        }
+    });
+    unsafe {
+        core::arch::asm!("csrw satp, {0}", in(reg) saved);
+        riscv::asm::sfence_vma_all();
+    }
+    code
+}
+
+/// 只支持标准输出和标准错误，内容必须是 UTF-8。
+fn write(_: &mut TrapContext, [fd, buf, len, ..]: [usize; 6]) -> isize {
+    if fd != 1 && fd != 2 {
+        return EBADF;
+    }
+    // 缓冲区必须在用户地址范围内，每页都已映射且用户态可读，否则内核访问时缺页无法处理
+    match buf.checked_add(len) {
+        Some(end) if trap::check_user(buf..end, false) => {}
+        _ => return EFAULT,
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(buf as *const u8, len) };
+    match core::str::from_utf8(bytes) {
+        Ok(s) => {
+            print!("{s}");
+            len as _
+        }
+        Err(_) => EINVAL,
+    }
+}
+
+/// 目前只有一个用户程序，直接返回。
+fn yield_(_: &mut TrapContext, _: [usize; 6]) -> isize {
+    0
+}
+
+fn counters(ctx: &mut TrapContext, _: [usize; 6]) -> isize {
+    let (cycle, instret): (usize, usize);
+    unsafe {
+        core::arch::asm!(
+            "csrr {0}, cycle",
+            "csrr {1}, instret",
+            out(reg) cycle,
+            out(reg) instret,
+        )
+    };
+    ctx.x[11] = instret;
+    cycle as _
+}
+
+bench!(yield_64, |b| match satp::read().mode() {
+    satp::Mode::Sv57 => yield_64_in::<page_table::Sv57>(b),
+    satp::Mode::Sv48 => yield_64_in::<page_table::Sv48>(b),
+    _ => yield_64_in::<page_table::Sv39>(b),
+});
+
+/// 运行一个调用 64 次 `yield` 后退出的用户程序，测量进出用户态的开销。
+fn yield_64_in<Meta: PagingMode>(b: &mut crate::bench::Bencher) {
+    use crate::space::PagePolicy;
+    use page_table::{VAddr, VmFlags, PPN, VPN};
+    const CODE: usize = 0x1000_0000;
+    const STACK: usize = 0x2000_0000;
+    const STACK_PAGES: usize = 4;
+    let mut space = AddressSpace::<Meta, Global>::user(Global);
+    // 程序就在内核镜像中，把它所在的页映射给用户态
+    let code = VAddr::<Meta>::new(CODE).floor();
+    let ppn = unsafe { LAYOUT.v_to_p(yield_program as usize) } >> Meta::PAGE_BITS;
+    space.map_to(
+        code..VPN::new(code.val() + 1),
+        PPN::new(ppn),
+        VmFlags::build_from_str("DA_UX_RV"),
+        PagePolicy::Small,
+    );
+    let stack = VAddr::<Meta>::new(STACK).floor();
+    let stack = stack..VPN::new(stack.val() + STACK_PAGES);
+    let sp = stack.end.base().val();
+    let flags = VmFlags::build_from_str("DA_U_WRV");
+    space.map(stack, flags, PagePolicy::Small);
+    let space = Mutex::new(space);
+    b.iter(|| {
+        let mut ctx = UserContext::new(CODE, sp);
+        assert_eq!(run(&space, &mut ctx), 0);
+    });
+}
+
+/// 调用 64 次 `yield` 后以 0 退出。
+///
+/// 按页对齐，映射到用户地址空间的第一条指令就是入口。
+#[naked]
+#[repr(align(4096))]
+unsafe extern "C" fn yield_program() -> ! {
+    core::arch::asm!(
+        "   li   s0, 64",
+        "1: li   a7, {yield_}",
+        "   ecall",
+        "   addi s0, s0, -1",
+        "   bnez s0, 1b",
+        "   li   a0, 0",
+        "   li   a7, {exit}",
+        "   ecall",
+        "   j    .",
+        yield_ = const YIELD,
+        exit   = const EXIT,
+        options(noreturn),
+    )
+}
@@ -1,55 +1,58 @@
-//! 在 kernel 的 build.rs 和 src 之间共享常量。
+//! 在 kernel 的 build.rs 和 src 之间共享常量，生成链接脚本。
 
 #![no_std]
 #![deny(warnings, missing_docs)]
 
-use core::fmt::{Display, Formatter, Result};
+extern crate alloc;
 
-/// 内核链接位置。
-pub const START: usize = 0xffff_ffc0_8020_0000;
+mod script;
 
-/// 链接脚本。
-pub struct Script;
+pub use script::{Constants, Script, ScriptBuilder, Section};
 
-impl Display for Script {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-        writeln!(
-            f,
-            "\
-OUTPUT_ARCH(riscv)
-ENTRY(_start)
-SECTIONS {{
-    . = {START};
-    .text : {{
-        *(.text.entry)
-        *(.text .text.*)
-    }}
-    .rodata : {{
-        *(.rodata .rodata.*)
-        *(.srodata .srodata.*)
-    }}
-    .data : {{
-        *(.data .data.*)
-        *(.sdata .sdata.*)
-    }}
-    .bss : ALIGN(8) {{
-        _bss = .;
-        *(.bss .bss.*)
-        *(.sbss .sbss.*)
-    }}
-    _end = ALIGN(8);
-}}"
-        )
-    }
+/// 以 pc 相对寻址取符号地址。
+///
+/// 重定位之前不能经过全局偏移表。
+#[cfg(target_arch = "riscv64")]
+macro_rules! symbol {
+    ($name:ident) => {{
+        let addr: usize;
+        core::arch::asm!(concat!("lla {}, ", stringify!($name)), out(reg) addr);
+        addr
+    }};
 }
 
+/// 默认的内核链接位置。
+///
+/// 即 Sv39 下的线性区偏移加上默认的物理加载位置 [`LOAD`]，按这个位置加载时不需要重定位。
+/// 内核是位置无关的，运行位置由 [`MemInfo::locate`] 按分页模式选取。
+pub const START: usize = 0xffff_ffc0_8020_0000;
+
+/// `va_bits` 位虚地址的分页模式下的线性区偏移。
+///
+/// 即高半规范地址的最低处，物理地址 0 映射到这里，物理地址在 `1 << (va_bits - 1)` 以内的内存都可以线性映射。
+/// Sv39、Sv48 和 Sv57 下分别是 256 GiB、128 TiB 和 64 PiB。
+pub const fn linear_offset(va_bits: usize) -> usize {
+    !0 << (va_bits - 1)
+}
+
+/// 默认的物理加载位置。
+///
+/// QEMU virt 上 SBI 固件跳转到这个地址。内核是位置无关的，加载到其他位置也能运行。
+pub const LOAD: usize = 0x8020_0000;
+
 /// 内核地址信息。
 #[derive(Clone, Copy, Debug)]
 pub struct MemInfo {
     /// 线性区偏移。
     pub offset: usize,
-    /// 内核虚地址。
+    /// 链接位置。
+    pub link: usize,
+    /// 内核虚地址，也是 .text 的起始位置。
     pub start: usize,
+    /// .rodata 虚地址，4 KiB 对齐。之后到 .data 之间的段都是只读的。
+    pub rodata: usize,
+    /// .data 虚地址，4 KiB 对齐。之后的段都是可写的。
+    pub data: usize,
     /// .bss 虚地址。
     pub bss: usize,
     /// 内核结束位置虚地址。
@@ -60,30 +63,42 @@ impl MemInfo {
     /// 非零初始化，避免 bss。
     pub const INIT: Self = Self {
         offset: usize::MAX,
+        link: usize::MAX,
         start: usize::MAX,
+        rodata: usize::MAX,
+        data: usize::MAX,
         bss: usize::MAX,
         end: usize::MAX,
     };
 
-    /// 定位内核内核内存信息。
+    /// 链接脚本必须定义的符号，[`ScriptBuilder::build`] 检查。
+    pub const SYMBOLS: [&'static str; 3] = ["_rodata", "_data", "_bss"];
+
+    /// 定位内核内存信息，`link` 是链接脚本中的链接位置，`offset` 是分页模式的线性区偏移，见 [`linear_offset`]。
+    ///
+    /// 内核是位置无关的，运行位置就是物理地址在线性区中的位置，因此线性区偏移与分页模式的大页总是对齐的。
     ///
     /// # Safety
     ///
     /// 在物理地址空间中调用，用于自动定位内核物理地址。
+    #[cfg(target_arch = "riscv64")]
     #[inline]
-    pub unsafe fn locate() -> Self {
-        extern "C" {
-            fn _start();
-            fn _bss();
-            fn _end();
-        }
-
-        let offset = START - _start as usize;
+    pub unsafe fn locate(link: usize, offset: usize) -> Self {
+        let start = symbol!(_start).wrapping_add(offset);
         Self {
             offset,
-            start: _start as usize + offset,
-            bss: _bss as usize + offset,
-            end: _end as usize + offset,
+            link,
+            start,
+            rodata: symbol!(_rodata).wrapping_add(offset),
+            data: symbol!(_data).wrapping_add(offset),
+            bss: symbol!(_bss).wrapping_add(offset),
+            end: symbol!(_end).wrapping_add(offset),
         }
     }
+
+    /// 运行位置相对链接位置的偏移，重定位时加到绝对地址上。
+    #[inline]
+    pub const fn bias(&self) -> usize {
+        self.start.wrapping_sub(self.link)
+    }
 }
@@ -0,0 +1,426 @@
+//! 链接脚本生成。
+
+use crate::{MemInfo, START};
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter, Result};
+
+/// 链接脚本。
+///
+/// 用 [`Script::builder`] 构造，[`Display`] 输出链接脚本文本，[`Script::constants`] 输出匹配的 Rust 常量。
+pub struct Script {
+    start: usize,
+    load: Option<usize>,
+    page_size: usize,
+    sections: Vec<Section>,
+    discard: Vec<&'static str>,
+}
+
+/// [`Script`] 的构造器。
+pub struct ScriptBuilder(Script);
+
+/// 一个输出段。
+pub struct Section {
+    name: &'static str,
+    align: Option<Align>,
+    items: Vec<Item>,
+}
+
+/// 输出段的对齐方式。
+#[derive(Clone, Copy)]
+enum Align {
+    /// 按脚本的页大小对齐。
+    Page,
+    /// 按给定的字节数对齐。
+    Bytes(usize),
+}
+
+/// 输出段中的一项，按添加的顺序输出。
+enum Item {
+    /// `*(pattern)`
+    Input(&'static str),
+    /// `KEEP(*(pattern))`
+    Keep(&'static str),
+    /// `symbol = .;`
+    Symbol(&'static str),
+}
+
+impl Script {
+    /// 从链接位置 [`START`]、4 KiB 页、没有输出段开始构造。
+    pub fn builder() -> ScriptBuilder {
+        ScriptBuilder(Self {
+            start: START,
+            load: None,
+            page_size: 4096,
+            sections: Vec::new(),
+            discard: Vec::new(),
+        })
+    }
+
+    /// 与脚本匹配的 Rust 常量，写到文件中供 `include!` 使用。
+    pub fn constants(&self) -> Constants<'_> {
+        Constants(self)
+    }
+}
+
+impl ScriptBuilder {
+    /// 设置链接位置。
+    ///
+    /// 应位于最高的 256 GiB 内，见 [`START`]。
+    pub fn start(mut self, start: usize) -> Self {
+        self.0.start = start;
+        self
+    }
+
+    /// 设置物理加载位置，各段的加载地址（LMA）与虚地址（VMA）相差 `start - load`，`load` 不能高于链接位置。
+    ///
+    /// 这样 ELF 可以直接交给 QEMU `-kernel`、GDB 等按物理地址加载，符号仍然是链接位置的虚地址。
+    /// 不设置时加载地址与虚地址相同。
+    pub fn load(mut self, load: usize) -> Self {
+        self.0.load = Some(load);
+        self
+    }
+
+    /// 设置页大小，[`Section::page_aligned`] 的段按它对齐。
+    pub fn page_size(mut self, page_size: usize) -> Self {
+        assert!(
+            page_size.is_power_of_two(),
+            "page size {page_size} is not a power of two"
+        );
+        self.0.page_size = page_size;
+        self
+    }
+
+    /// 同 [`page_size`](Self::page_size)。
+    pub fn align(self, align: usize) -> Self {
+        self.page_size(align)
+    }
+
+    /// 按顺序添加输出段。
+    pub fn section(mut self, section: Section) -> Self {
+        self.0.sections.push(section);
+        self
+    }
+
+    /// 丢弃匹配 `pattern` 的输入段。
+    pub fn discard(mut self, pattern: &'static str) -> Self {
+        self.0.discard.push(pattern);
+        self
+    }
+
+    /// 检查加载位置不高于链接位置、[`MemInfo`] 需要的符号都已定义，完成构造。
+    pub fn build(self) -> Script {
+        if let Some(load) = self.0.load {
+            assert!(
+                load <= self.0.start,
+                "load address {load:#x} is above link address {:#x}",
+                self.0.start
+            );
+        }
+        for symbol in MemInfo::SYMBOLS {
+            let defined = self.0.sections.iter().any(|section| {
+                section
+                    .items
+                    .iter()
+                    .any(|item| matches!(item, Item::Symbol(s) if *s == symbol))
+            });
+            assert!(
+                defined,
+                "symbol {symbol} required by MemInfo is not defined"
+            );
+        }
+        self.0
+    }
+}
+
+impl Section {
+    /// 名为 `name` 的空输出段。
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            align: None,
+            items: Vec::new(),
+        }
+    }
+
+    /// 起始位置按页对齐。
+    pub fn page_aligned(mut self) -> Self {
+        self.align = Some(Align::Page);
+        self
+    }
+
+    /// 起始位置按 `align` 字节对齐。
+    pub fn align(mut self, align: usize) -> Self {
+        assert!(
+            align.is_power_of_two(),
+            "align {align} is not a power of two"
+        );
+        self.align = Some(Align::Bytes(align));
+        self
+    }
+
+    /// 放入匹配 `pattern` 的输入段，`pattern` 可以包含多个空白分隔的模式。
+    pub fn input(mut self, pattern: &'static str) -> Self {
+        self.items.push(Item::Input(pattern));
+        self
+    }
+
+    /// 放入匹配 `pattern` 的输入段，即使没有被引用也保留，用于链接时收集的注册表。
+    pub fn keep(mut self, pattern: &'static str) -> Self {
+        self.items.push(Item::Keep(pattern));
+        self
+    }
+
+    /// 在当前位置定义符号 `name`。
+    pub fn symbol(mut self, name: &'static str) -> Self {
+        self.items.push(Item::Symbol(name));
+        self
+    }
+}
+
+impl Display for Script {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        writeln!(f, "OUTPUT_ARCH(riscv)")?;
+        writeln!(f, "ENTRY(_start)")?;
+        writeln!(f, "SECTIONS {{")?;
+        writeln!(f, "    . = {:#x};", self.start)?;
+        for section in &self.sections {
+            let name = section.name;
+            write!(f, "    {name} :")?;
+            if let Some(load) = self.load {
+                write!(f, " AT(ADDR({name}) - {:#x})", self.start - load)?;
+            }
+            match section.align {
+                Some(Align::Page) => write!(f, " ALIGN({})", self.page_size)?,
+                Some(Align::Bytes(n)) => write!(f, " ALIGN({n})")?,
+                None => {}
+            }
+            writeln!(f, " {{")?;
+            for item in &section.items {
+                match item {
+                    Item::Input(pattern) => writeln!(f, "        *({pattern})")?,
+                    Item::Keep(pattern) => writeln!(f, "        KEEP(*({pattern}))")?,
+                    Item::Symbol(name) => writeln!(f, "        {name} = .;")?,
+                }
+            }
+            writeln!(f, "    }}")?;
+        }
+        writeln!(f, "    _end = ALIGN(8);")?;
+        if !self.discard.is_empty() {
+            writeln!(f, "    /DISCARD/ : {{")?;
+            for pattern in &self.discard {
+                writeln!(f, "        *({pattern})")?;
+            }
+            writeln!(f, "    }}")?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+/// 与链接脚本匹配的 Rust 常量。
+///
+/// 包括链接位置、加载位置、页大小和脚本定义的符号，符号按地址顺序排列，最后是 `_end`。
+pub struct Constants<'a>(&'a Script);
+
+impl Display for Constants<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let script = self.0;
+        writeln!(f, "/// 内核链接位置。")?;
+        writeln!(f, "pub const START: usize = {:#x};", script.start)?;
+        writeln!(f, "/// 物理加载位置，没有设置时加载地址与虚地址相同。")?;
+        match script.load {
+            Some(load) => writeln!(f, "pub const LOAD: Option<usize> = Some({load:#x});")?,
+            None => writeln!(f, "pub const LOAD: Option<usize> = None;")?,
+        }
+        writeln!(f, "/// 页大小，按页对齐的段以它为单位。")?;
+        writeln!(f, "pub const PAGE_SIZE: usize = {:#x};", script.page_size)?;
+        let symbols = script
+            .sections
+            .iter()
+            .flat_map(|section| &section.items)
+            .filter_map(|item| match item {
+                Item::Symbol(name) => Some(*name),
+                _ => None,
+            })
+            .chain(["_end"]);
+        writeln!(f, "/// 链接脚本定义的符号，包括 `MemInfo` 需要的全部符号。")?;
+        write!(f, "pub const SYMBOLS: &[&str] = &[")?;
+        for (i, name) in symbols.enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{name:?}")?;
+        }
+        writeln!(f, "];")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    /// 定义了 [`MemInfo::SYMBOLS`] 的最小脚本。
+    fn builder() -> ScriptBuilder {
+        Script::builder()
+            .section(
+                Section::new(".text")
+                    .input(".text.entry")
+                    .input(".text .text.*"),
+            )
+            .section(
+                Section::new(".rodata")
+                    .page_aligned()
+                    .symbol("_rodata")
+                    .input(".rodata .rodata.*"),
+            )
+            .section(
+                Section::new(".bench")
+                    .align(8)
+                    .symbol("_bench_start")
+                    .keep(".bench")
+                    .symbol("_bench_end"),
+            )
+            .section(
+                Section::new(".data")
+                    .page_aligned()
+                    .symbol("_data")
+                    .input(".data"),
+            )
+            .section(Section::new(".bss").align(8).symbol("_bss").input(".bss"))
+    }
+
+    #[test]
+    fn script_text() {
+        let script = builder().page_size(0x1000).discard(".comment").build();
+        assert_eq!(
+            script.to_string(),
+            "\
+OUTPUT_ARCH(riscv)
+ENTRY(_start)
+SECTIONS {
+    . = 0xffffffc080200000;
+    .text : {
+        *(.text.entry)
+        *(.text .text.*)
+    }
+    .rodata : ALIGN(4096) {
+        _rodata = .;
+        *(.rodata .rodata.*)
+    }
+    .bench : ALIGN(8) {
+        _bench_start = .;
+        KEEP(*(.bench))
+        _bench_end = .;
+    }
+    .data : ALIGN(4096) {
+        _data = .;
+        *(.data)
+    }
+    .bss : ALIGN(8) {
+        _bss = .;
+        *(.bss)
+    }
+    _end = ALIGN(8);
+    /DISCARD/ : {
+        *(.comment)
+    }
+}
+"
+        );
+    }
+
+    #[test]
+    fn page_aligned_follows_page_size() {
+        let script = builder().page_size(0x10000).build().to_string();
+        assert!(script.contains("    .rodata : ALIGN(65536) {\n"));
+        assert!(script.contains("    .data : ALIGN(65536) {\n"));
+        assert!(script.contains("    .bss : ALIGN(8) {\n"));
+    }
+
+    #[test]
+    fn no_discard_without_patterns() {
+        let script = builder().build().to_string();
+        assert!(!script.contains("/DISCARD/"));
+        assert!(script.ends_with("    _end = ALIGN(8);\n}\n"));
+    }
+
+    #[test]
+    fn load_address() {
+        let script = builder().load(0x8020_0000).build().to_string();
+        let headers = script
+            .lines()
+            .filter(|line| line.ends_with('{') && line.starts_with("    ."))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            headers,
+            [
+                "    .text : AT(ADDR(.text) - 0xffffffc000000000) {",
+                "    .rodata : AT(ADDR(.rodata) - 0xffffffc000000000) ALIGN(4096) {",
+                "    .bench : AT(ADDR(.bench) - 0xffffffc000000000) ALIGN(8) {",
+                "    .data : AT(ADDR(.data) - 0xffffffc000000000) ALIGN(4096) {",
+                "    .bss : AT(ADDR(.bss) - 0xffffffc000000000) ALIGN(8) {",
+            ]
+        );
+        // 不设置加载位置时不输出 AT()
+        assert!(!builder().build().to_string().contains("AT("));
+    }
+
+    #[test]
+    #[should_panic(expected = "symbol _bss required by MemInfo is not defined")]
+    fn build_requires_meminfo_symbols() {
+        Script::builder()
+            .section(Section::new(".rodata").symbol("_rodata"))
+            .section(Section::new(".data").symbol("_data"))
+            .build();
+    }
+
+    #[test]
+    #[should_panic(expected = "load address 0xffffffc080400000 is above link address")]
+    fn load_above_start() {
+        builder().load(0xffff_ffc0_8040_0000).build();
+    }
+
+    #[test]
+    #[should_panic(expected = "page size 3000 is not a power of two")]
+    fn page_size_power_of_two() {
+        let _ = Script::builder().page_size(3000);
+    }
+
+    #[test]
+    fn align_is_page_size() {
+        let script = builder().align(0x10000).build().to_string();
+        assert!(script.contains("    .rodata : ALIGN(65536) {\n"));
+    }
+
+    #[test]
+    #[should_panic(expected = "align 12 is not a power of two")]
+    fn section_align_power_of_two() {
+        let _ = Section::new(".bss").align(12);
+    }
+
+    #[test]
+    fn constants() {
+        let script = builder()
+            .start(0xffff_ffc0_8000_0000)
+            .load(0x8000_0000)
+            .build();
+        assert_eq!(
+            script.constants().to_string(),
+            "\
+/// 内核链接位置。
+pub const START: usize = 0xffffffc080000000;
+/// 物理加载位置，没有设置时加载地址与虚地址相同。
+pub const LOAD: Option<usize> = Some(0x80000000);
+/// 页大小，按页对齐的段以它为单位。
+pub const PAGE_SIZE: usize = 0x1000;
+/// 链接脚本定义的符号，包括 `MemInfo` 需要的全部符号。
+pub const SYMBOLS: &[&str] = &[\"_rodata\", \"_bench_start\", \"_bench_end\", \"_data\", \"_bss\", \"_end\"];
+"
+        );
+        let script = builder().build();
+        assert!(script
+            .constants()
+            .to_string()
+            .contains("pub const LOAD: Option<usize> = None;\n"));
+    }
+}
@@ -0,0 +1,254 @@
+use crate::{BuildArgs, PROJECT};
+use command_ext::CommandExt;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{BufRead, BufReader},
+    process::Stdio,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// 基准测试名 -> 指标名 -> 值。
+type Results = BTreeMap<String, BTreeMap<String, u64>>;
+
+/// 与基线比较的指标。
+const COMPARED: [&str; 2] = ["cycles.median", "ns.median"];
+
+#[derive(Args)]
+pub(crate) struct BenchArgs {
+    #[clap(flatten)]
+    build: BuildArgs,
+    /// benchmarks to run, comma separated name fragments
+    #[clap(long, default_value = "all")]
+    filter: String,
+    /// timed iterations per benchmark
+    #[clap(long, default_value = "100")]
+    iters: usize,
+    /// extra PMU events, comma separated
+    #[clap(long)]
+    events: Option<String>,
+    /// seconds to wait for all benchmarks
+    #[clap(long, default_value = "300")]
+    timeout: u64,
+    /// baseline name, stored as target/bench/<name>.json
+    #[clap(long, default_value = "baseline")]
+    baseline: String,
+    /// save this run as the baseline instead of comparing
+    #[clap(long)]
+    save_baseline: bool,
+    /// regression threshold in percent
+    #[clap(long, default_value = "5")]
+    threshold: f64,
+}
+
+impl BenchArgs {
+    pub fn bench(&self) {
+        self.build.make();
+        let results = self.run();
+        if results.is_empty() {
+            eprintln!("no benchmark result");
+            std::process::exit(1);
+        }
+        let dir = PROJECT.join("target").join("bench");
+        fs::create_dir_all(&dir).unwrap();
+        let latest = dir.join("latest.json");
+        fs::write(&latest, serde_json::to_string_pretty(&results).unwrap()).unwrap();
+        println!("results saved to {}", latest.display());
+
+        let baseline = dir.join(format!("{}.json", self.baseline));
+        if self.save_baseline {
+            fs::copy(&latest, &baseline).unwrap();
+            println!("baseline saved to {}", baseline.display());
+            return;
+        }
+        match fs::read_to_string(&baseline) {
+            Ok(json) => {
+                let base: Results = serde_json::from_str(&json).unwrap();
+                if compare(&base, &results, self.threshold) {
+                    std::process::exit(1);
+                }
+            }
+            Err(_) => println!(
+                "baseline {} not found, run with --save-baseline to create it",
+                baseline.display()
+            ),
+        }
+    }
+
+    /// 在 QEMU 中运行基准测试，返回解析出的结果。
+    fn run(&self) -> Results {
+        let mut cmdline = format!("bench={} bench-iters={}", self.filter, self.iters);
+        if let Some(events) = &self.events {
+            cmdline.push_str(&format!(" bench-events={events}"));
+        }
+        if let Some(append) = &self.build.append {
+            cmdline.push(' ');
+            cmdline.push_str(append);
+        }
+        let mut qemu = self
+            .build
+            .qemu_command(Some(&cmdline))
+            .args(["-serial", "stdio", "-display", "none", "-monitor", "none"]);
+        let mut child = qemu
+            .as_mut()
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("failed to start qemu");
+
+        // 在另一个线程读串口，主线程负责超时
+        let stdout = child.stdout.take().unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        let deadline = Instant::now() + Duration::from_secs(self.timeout);
+        let mut results = Results::new();
+        let finished = loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match rx.recv_timeout(timeout) {
+                Ok(line) => {
+                    let line = line.trim_end();
+                    println!("{line}");
+                    if line == "BENCH-END" {
+                        break true;
+                    }
+                    if let Some((name, metrics)) = parse(line) {
+                        results.insert(name, metrics);
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    eprintln!("timeout after {} s", self.timeout);
+                    break false;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => break false,
+            }
+        };
+        let _ = child.kill();
+        let _ = child.wait();
+        if !finished {
+            eprintln!("benchmarks did not finish");
+            std::process::exit(1);
+        }
+        results
+    }
+}
+
+/// 解析 `BENCH name=... key=value ...`，跳过 `n/a`。
+fn parse(line: &str) -> Option<(String, BTreeMap<String, u64>)> {
+    let mut fields = line.strip_prefix("BENCH ")?.split_whitespace();
+    let name = fields.next()?.strip_prefix("name=")?.to_string();
+    let metrics = fields
+        .filter_map(|field| field.split_once('='))
+        .filter_map(|(key, value)| Some((key.to_string(), value.parse().ok()?)))
+        .collect();
+    Some((name, metrics))
+}
+
+/// 打印比较表，有退步时返回 `true`。
+fn compare(base: &Results, current: &Results, threshold: f64) -> bool {
+    let width = current.keys().map(String::len).max().unwrap_or(0).max(9);
+    println!(
+        "{:width$}  {:>13}  {:>12}  {:>12}  {:>8}",
+        "benchmark", "metric", "baseline", "current", "change"
+    );
+    let mut regressed = false;
+    for (name, metrics) in current {
+        for metric in COMPARED {
+            let now = match metrics.get(metric) {
+                Some(value) => *value,
+                None => continue,
+            };
+            let before = base.get(name).and_then(|m| m.get(metric));
+            let (before, change, mark) = match before {
+                Some(&before) if before > 0 => {
+                    let change = (now as f64 - before as f64) / before as f64 * 100.0;
+                    let mark = if change > threshold {
+                        regressed = true;
+                        "  REGRESSED"
+                    } else if change < -threshold {
+                        "  improved"
+                    } else {
+                        ""
+                    };
+                    (before.to_string(), format!("{change:+.1}%"), mark)
+                }
+                _ => ("-".into(), "new".into(), ""),
+            };
+            println!("{name:width$}  {metric:>13}  {before:>12}  {now:>12}  {change:>8}{mark}");
+        }
+    }
+    for name in base.keys().filter(|name| !current.contains_key(*name)) {
+        println!("{name:width$}  missing in this run");
+    }
+    if regressed {
+        println!("regression over {threshold}% detected");
+    }
+    regressed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn results(entries: &[(&str, &[(&str, u64)])]) -> Results {
+        entries
+            .iter()
+            .map(|(name, metrics)| {
+                let metrics = metrics.iter().map(|(k, v)| (k.to_string(), *v)).collect();
+                (name.to_string(), metrics)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_bench_line() {
+        let (name, metrics) = parse(
+            "BENCH name=kernel::timer::instant_now iters=100 cycles.median=13 ns.median=1300 cache-misses=n/a",
+        )
+        .unwrap();
+        assert_eq!(name, "kernel::timer::instant_now");
+        assert_eq!(metrics.len(), 3);
+        assert_eq!(metrics["iters"], 100);
+        assert_eq!(metrics["cycles.median"], 13);
+        assert_eq!(metrics["ns.median"], 1300);
+        assert!(!metrics.contains_key("cache-misses"));
+    }
+
+    #[test]
+    fn parse_other_lines() {
+        assert!(parse("BENCH-END").is_none());
+        assert!(parse("[ INFO] hart 0 online").is_none());
+        assert!(parse("BENCH iters=100").is_none());
+    }
+
+    #[test]
+    fn compare_threshold() {
+        let base = results(&[("a", &[("cycles.median", 100), ("ns.median", 100)])]);
+        let within = results(&[("a", &[("cycles.median", 104), ("ns.median", 96)])]);
+        assert!(!compare(&base, &within, 5.0));
+        let slower = results(&[("a", &[("cycles.median", 106), ("ns.median", 100)])]);
+        assert!(compare(&base, &slower, 5.0));
+        let faster = results(&[("a", &[("cycles.median", 50), ("ns.median", 50)])]);
+        assert!(!compare(&base, &faster, 5.0));
+    }
+
+    #[test]
+    fn compare_new_and_missing() {
+        let base = results(&[
+            ("old", &[("cycles.median", 100)]),
+            ("zero", &[("ns.median", 0)]),
+        ]);
+        let current = results(&[
+            ("new", &[("cycles.median", 1000)]),
+            ("zero", &[("ns.median", 9)]),
+        ]);
+        assert!(!compare(&base, &current, 5.0));
+    }
+}
@@ -1,6 +1,9 @@
 #[macro_use]
 extern crate clap;
 
+mod bench;
+
+use bench::BenchArgs;
 use clap::Parser;
 use command_ext::{BinUtil, Cargo, CommandExt, Qemu};
 use once_cell::sync::Lazy;
@@ -29,6 +32,7 @@ enum Commands {
     Make(BuildArgs),
     Asm(BuildArgs),
     Qemu(BuildArgs),
+    Bench(BenchArgs),
 }
 
 fn main() {
@@ -37,6 +41,7 @@ fn main() {
         Make(args) => args.make(),
         Asm(args) => args.asm(),
         Qemu(args) => args.qemu(),
+        Bench(args) => args.bench(),
     }
 }
 
@@ -45,6 +50,18 @@ struct BuildArgs {
     /// log level
     #[clap(long)]
     log: Option<String>,
+    /// number of harts
+    #[clap(long, default_value = "1")]
+    smp: usize,
+    /// next-stage payload embedded into the kernel
+    #[clap(long)]
+    payload: Option<PathBuf>,
+    /// next-stage payload passed to qemu as initrd
+    #[clap(long)]
+    initrd: Option<PathBuf>,
+    /// kernel command line
+    #[clap(long)]
+    append: Option<String>,
 }
 
 impl BuildArgs {
@@ -54,6 +71,9 @@ impl BuildArgs {
             .optional(&self.log, |cargo, level| {
                 cargo.env("LOG", level);
             })
+            .optional(&self.payload, |cargo, payload| {
+                cargo.env("PAYLOAD", fs::canonicalize(payload).unwrap());
+            })
             .release()
             .target(TARGET_ARCH)
             .invoke();
@@ -68,31 +88,29 @@ impl BuildArgs {
 
     fn qemu(&self) {
         self.make();
+        self.qemu_command(self.append.as_deref())
+            .args(["-serial", "mon:stdio"])
+            .arg("-nographic")
+            .invoke();
+    }
+
+    /// 运行内核的 QEMU 命令，`cargo qemu` 和 `cargo xtask bench` 共用，串口等交互方式由调用者补充。
+    fn qemu_command(&self, append: Option<&str>) -> Qemu {
         let elf = TARGET.join("release").join("kernel");
         Qemu::system("riscv64")
             .args(["-machine", "virt"])
             .arg("-bios")
             .arg(PROJECT.join("rustsbi-qemu.bin"))
             .arg("-kernel")
-            .arg(objcopy(elf, true))
-            .args(["-smp", "1"])
-            .args(["-serial", "mon:stdio"])
+            .arg(elf)
+            .optional(&self.initrd, |qemu, initrd| {
+                qemu.arg("-initrd").arg(initrd);
+            })
+            .optional(&append, |qemu, append| {
+                qemu.arg("-append").arg(append);
+            })
+            .arg("-smp")
+            .arg(self.smp.to_string())
             .args(["-m", "2G"])
-            .arg("-nographic")
-            .invoke();
     }
 }
-
-fn objcopy(elf: impl AsRef<Path>, binary: bool) -> PathBuf {
-    let elf = elf.as_ref();
-    let bin = elf.with_extension("bin");
-    BinUtil::objcopy()
-        .arg(elf)
-        .arg("--strip-all")
-        .conditional(binary, |binutil| {
-            binutil.args(["-O", "binary"]);
-        })
-        .arg(&bin)
-        .invoke();
-    bin
-}